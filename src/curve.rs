@@ -0,0 +1,95 @@
+/// Value of a [`ParticleCurve`] that can be interpolated between keys.
+pub trait CurveValue: Copy {
+    fn lerp(self, other: Self, t: f32) -> Self;
+}
+
+impl CurveValue for f32 {
+    fn lerp(self, other: Self, t: f32) -> Self {
+        self + (other - self) * t
+    }
+}
+
+impl CurveValue for [f32; 4] {
+    fn lerp(self, other: Self, t: f32) -> Self {
+        [
+            self[0].lerp(other[0], t),
+            self[1].lerp(other[1], t),
+            self[2].lerp(other[2], t),
+            self[3].lerp(other[3], t),
+        ]
+    }
+}
+
+/// Piecewise linear curve over a particle's lifetime.
+///
+/// Keys are (t, value) pairs, where t is 0 when a particle spawns and 1
+/// when it dies. Values before the first key and after the last are held.
+/// Serialized as a list of keys, which are sorted when deserialized.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(
+    from = "Vec<(f32, T)>",
+    into = "Vec<(f32, T)>",
+    bound(serialize = "T: Clone + serde::Serialize", deserialize = "T: serde::Deserialize<'de>"),
+))]
+pub struct ParticleCurve<T> {
+    keys: Vec<(f32, T)>,
+}
+impl<T: CurveValue> ParticleCurve<T> {
+    /// Create curve from keys in any order. Keys with a NaN time are dropped.
+    pub fn new(keys: &[(f32, T)]) -> Self {
+        Self::from(keys.to_vec())
+    }
+
+    pub fn keys(&self) -> &[(f32, T)] {
+        &self.keys
+    }
+
+    /// Return value of curve at t, or None if the curve has no keys.
+    pub fn sample(&self, t: f32) -> Option<T> {
+        let next = self.keys.iter().position(|key| key.0 > t);
+        match next {
+            Some(0) => self.keys.first().map(|key| key.1),
+            Some(i) => {
+                let (t0, v0) = self.keys[i - 1];
+                let (t1, v1) = self.keys[i];
+                Some(v0.lerp(v1, (t - t0) / (t1 - t0)))
+            }
+            None => self.keys.last().map(|key| key.1),
+        }
+    }
+}
+
+impl<T> From<Vec<(f32, T)>> for ParticleCurve<T> {
+    fn from(mut keys: Vec<(f32, T)>) -> Self {
+        keys.retain(|key| !key.0.is_nan());
+        keys.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Self { keys }
+    }
+}
+
+impl<T> From<ParticleCurve<T>> for Vec<(f32, T)> {
+    fn from(curve: ParticleCurve<T>) -> Self {
+        curve.keys
+    }
+}
+
+
+#[test]
+fn sample_curve() {
+    let curve = ParticleCurve::new(&[(1.0, 0.0), (0.0, 1.0), (0.5, 3.0)]);
+    assert!(curve.sample(-1.0) == Some(1.0));
+    assert!(curve.sample(0.25) == Some(2.0));
+    assert!(curve.sample(0.75) == Some(1.5));
+    assert!(curve.sample(2.0) == Some(0.0));
+    assert!(ParticleCurve::<f32>::new(&[]).sample(0.5).is_none());
+    assert!(ParticleCurve::new(&[(f32::NAN, 1.0), (0.0, 2.0)]).keys() == [(0.0, 2.0)]);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn deserialize_unsorted() {
+    let curve: ParticleCurve<f32> = ron::from_str("[(1.0, 0.0), (0.0, 1.0)]").unwrap();
+    assert!(curve.keys() == [(0.0, 1.0), (1.0, 0.0)]);
+    assert!(ron::to_string(&curve).unwrap() == "[(0.0,1.0),(1.0,0.0)]");
+}
@@ -0,0 +1,591 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Serialize, Deserialize};
+
+use crate::error::{BrumousError, BrumousResult};
+use crate::particle_system::{
+    ParticleSystem,
+    ParticleSystemSet,
+    ParticleAnimation,
+    ParticleChannel,
+    ParticleParameter,
+    ParticleBinding,
+};
+#[cfg(feature = "wgpu")]
+use crate::particle_system_renderer::ParticleSystemRenderer;
+use crate::curve::ParticleCurve;
+use crate::expr::Expr;
+use crate::reload::FileWatcher;
+use crate::frustum::OffscreenMode;
+use crate::quaternion::Quaternion;
+use crate::ParticleSystemDescriptor;
+use crate::ParticleSystemBounds;
+#[cfg(feature = "wgpu")]
+use crate::{ParticleSystemRendererDescriptor, ParticleMeshType, DepthTextureDescriptor};
+use crate::SimulationSpace;
+use crate::SortMode;
+use crate::LodBand;
+use crate::DEFAULT_MAX_LIGHTS;
+
+/// An effect made of one or more particle systems and the renderer that
+/// draws them, stored as RON so effects can be edited without recompiling.
+///
+/// Fields missing from a file are given their default values.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EffectDescriptor {
+    pub systems:  Vec<EffectSystemDescriptor>,
+    pub renderer: EffectRendererDescriptor,
+}
+impl EffectDescriptor {
+    /// Parse an effect from RON text.
+    pub fn from_ron(data: &str) -> BrumousResult<Self> {
+        ron::from_str(data)
+            .map_err(|err| BrumousError::ParseEffect(String::from("<string>"), err.to_string()))
+    }
+
+    /// Load an effect from a RON file.
+    pub fn load<P: AsRef<Path>>(path: P) -> BrumousResult<Self> {
+        let path = path.as_ref();
+        let data = fs::read_to_string(path)?;
+        ron::from_str(&data)
+            .map_err(|err| BrumousError::ParseEffect(path.display().to_string(), err.to_string()))
+    }
+
+    /// Return effect as RON text.
+    pub fn to_ron(&self) -> BrumousResult<String> {
+        ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::new())
+            .map_err(|err| BrumousError::SaveEffect(err.to_string()))
+    }
+
+    /// Save effect to a RON file.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> BrumousResult<()> {
+        fs::write(path, self.to_ron()?)?;
+        Ok(())
+    }
+
+    /// Create a set containing every system in the effect.
+    #[cfg(feature = "wgpu")]
+    pub fn create_systems(&self, device: &wgpu::Device) -> BrumousResult<ParticleSystemSet> {
+        let mut systems = Vec::with_capacity(self.systems.len());
+        for sys in self.systems.iter() {
+            systems.push(sys.create(device)?);
+        }
+        Ok(ParticleSystemSet::new(systems))
+    }
+
+    /// Create a set containing every system in the effect, without GPU
+    /// buffers, see [`ParticleSystem::headless`].
+    pub fn create_headless_systems(&self) -> BrumousResult<ParticleSystemSet> {
+        let mut systems = Vec::with_capacity(self.systems.len());
+        for sys in self.systems.iter() {
+            systems.push(sys.create_headless()?);
+        }
+        Ok(ParticleSystemSet::new(systems))
+    }
+
+    /// Create the renderer described by the effect.
+    #[cfg(feature = "wgpu")]
+    pub fn create_renderer(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        config: &wgpu::SurfaceConfiguration,
+    ) -> BrumousResult<ParticleSystemRenderer> {
+        let lod_meshes = self.renderer.lod_mesh_types();
+        ParticleSystemRenderer::new(device, queue, config, &self.renderer.descriptor(&lod_meshes))
+    }
+}
+
+/// Effect loaded from a file, which is reloaded in place when the file
+/// changes, see [`EffectFile::poll_changes`].
+pub struct EffectFile {
+    path:    PathBuf,
+    effect:  EffectDescriptor,
+    watcher: FileWatcher,
+}
+impl EffectFile {
+    pub fn load<P: AsRef<Path>>(path: P) -> BrumousResult<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut watcher = FileWatcher::new();
+        watcher.watch(&path);
+        Ok(
+            Self {
+                effect: EffectDescriptor::load(&path)?,
+                path,
+                watcher,
+            }
+        )
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Return effect as last loaded from the file.
+    pub fn effect(&self) -> &EffectDescriptor {
+        &self.effect
+    }
+
+    /// Reload the effect if its file, or the texture, meshes or shader of
+    /// the renderer, changed on disk. Returns whether anything was reloaded.
+    ///
+    /// Systems are matched by name and updated in place, keeping their
+    /// living particles and transform. Their forces, attractors, colliders
+    /// animations, expressions and bindings are replaced by those in the
+    /// file. Parameters already set keep their values. Systems removed
+    /// from the file are removed from the set, and new ones are created.
+    /// The renderer is rebuilt only if its settings changed. If the systems
+    /// or the renderer fail to build nothing is changed, and the effect is
+    /// reloaded on the next poll.
+    #[cfg(feature = "wgpu")]
+    pub fn poll_changes(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        set: &mut ParticleSystemSet,
+        rend: &mut ParticleSystemRenderer,
+    ) -> BrumousResult<bool> {
+        // Poll the effect even if the renderer fails to rebuild, returning
+        // the first error.
+        let rebuilt = rend.poll_changes(device, queue);
+        let reloaded = match self.poll_effect() {
+            Ok(Some(effect)) => {
+                let applied = self.apply_effect(device, queue, effect, set, rend);
+                if applied.is_err() {
+                    self.watcher.invalidate(&self.path);
+                }
+                applied.map(|_| true)
+            }
+            Ok(None) => Ok(false),
+            Err(err) => Err(err),
+        };
+        Ok(rebuilt? | reloaded?)
+    }
+
+    /// Apply a reloaded effect to the set and renderer. Nothing is changed
+    /// unless both the systems and the renderer can be built.
+    #[cfg(feature = "wgpu")]
+    fn apply_effect(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        effect: EffectDescriptor,
+        set: &mut ParticleSystemSet,
+        rend: &mut ParticleSystemRenderer,
+    ) -> BrumousResult<()> {
+        let rebuilt = if effect.renderer != self.effect.renderer {
+            let lod_meshes = effect.renderer.lod_mesh_types();
+            Some(rend.rebuilt(device, queue, &effect.renderer.descriptor(&lod_meshes))?)
+        }
+        else {
+            None
+        };
+        update_systems(&self.effect, &effect, set, |desc| desc.create(device))?;
+        for sys in set.systems_mut() {
+            sys.resize_buffers(device);
+        }
+        if let Some(rebuilt) = rebuilt {
+            *rend = rebuilt;
+        }
+        self.effect = effect;
+        Ok(())
+    }
+
+    /// Like [`EffectFile::poll_changes`], for systems without GPU buffers
+    /// and no renderer. New systems are created headless.
+    pub fn poll_changes_headless(&mut self, set: &mut ParticleSystemSet) -> BrumousResult<bool> {
+        let Some(effect) = self.poll_effect()? else {
+            return Ok(false);
+        };
+        let updated = update_systems(&self.effect, &effect, set, |desc| desc.create_headless());
+        if updated.is_err() {
+            self.watcher.invalidate(&self.path);
+        }
+        updated?;
+        self.effect = effect;
+        Ok(true)
+    }
+
+    /// Return the effect reloaded from the file, if it changed. A file that
+    /// fails to load, e.g. because it was caught mid-save, is read again on
+    /// the next poll.
+    fn poll_effect(&mut self) -> BrumousResult<Option<EffectDescriptor>> {
+        if self.watcher.poll_changes().is_empty() {
+            return Ok(None);
+        }
+        let effect = EffectDescriptor::load(&self.path);
+        if effect.is_err() {
+            self.watcher.invalidate(&self.path);
+        }
+        effect.map(Some)
+    }
+}
+
+/// Update systems of a set from a reloaded effect, matching them by name.
+fn update_systems<F>(
+    old: &EffectDescriptor,
+    effect: &EffectDescriptor,
+    set: &mut ParticleSystemSet,
+    create: F,
+) -> BrumousResult<()>
+where
+    F: Fn(&EffectSystemDescriptor) -> BrumousResult<ParticleSystem>,
+{
+    // Create new systems and check the others before touching the set,
+    // so that an invalid effect leaves it as it was.
+    let mut created = Vec::new();
+    for desc in effect.systems.iter() {
+        if set.get(&desc.name).is_some() {
+            desc.descriptor().validate()?;
+        }
+        else {
+            created.push(create(desc)?);
+        }
+    }
+
+    for old in old.systems.iter() {
+        if effect.systems.iter().all(|sys| sys.name != old.name) {
+            set.remove(&old.name);
+        }
+    }
+    for desc in effect.systems.iter() {
+        if let Some(sys) = set.get_mut(&desc.name) {
+            sys.apply_descriptor(&desc.descriptor())?;
+            sys.clear_forces();
+            sys.clear_colliders();
+            sys.clear_animations();
+            sys.clear_spawn_exprs();
+            sys.clear_bindings();
+            desc.add_effects(sys);
+        }
+    }
+    for sys in created {
+        set.insert(sys);
+    }
+    Ok(())
+}
+
+/// Settings of one particle system in an effect.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EffectSystemDescriptor {
+    pub name:               String,
+    pub max:                usize,
+    pub rate:               usize,
+    pub rate_over_distance: f32,
+    pub position:           [f32; 3],
+    /// Rotation quaternion in [s, x, y, z] order.
+    pub rotation:           [f32; 4],
+    pub scale:              [f32; 3],
+    pub space:              SimulationSpace,
+    pub inherit_velocity:   f32,
+    pub life:               f32,
+    pub looping:            bool,
+    pub sort:               SortMode,
+    pub offscreen:          OffscreenMode,
+    pub lod:                Vec<LodBand>,
+    pub material:           u32,
+    pub bounds:             ParticleSystemBounds,
+    pub forces:             Vec<[f32; 3]>,
+    pub attractors:         Vec<EffectAttractor>,
+    pub colliders:          Vec<EffectCollider>,
+    pub color_over_life:    Option<ParticleCurve<[f32; 4]>>,
+    pub scale_over_life:    Option<ParticleCurve<f32>>,
+    /// Expressions setting channels of new particles.
+    pub spawn:              Vec<(ParticleChannel, Expr)>,
+    /// Expressions setting channels of particles every frame.
+    pub animations:         Vec<(ParticleChannel, Expr)>,
+    /// Named parameters and their default values.
+    pub parameters:         Vec<(String, ParticleParameter)>,
+    /// Settings driven by expressions of the parameters.
+    pub bindings:           Vec<(ParticleBinding, Expr)>,
+}
+impl EffectSystemDescriptor {
+    /// Return descriptor of the system, borrowing strings and LOD bands.
+    pub fn descriptor(&self) -> ParticleSystemDescriptor<'_> {
+        ParticleSystemDescriptor {
+            max:                self.max,
+            rate:               self.rate,
+            rate_over_distance: self.rate_over_distance,
+            pos:                self.position.into(),
+            rotation:           Quaternion::from(self.rotation),
+            scale:              self.scale.into(),
+            space:              self.space,
+            inherit_velocity:   self.inherit_velocity,
+            name:               &self.name,
+            life:               self.life,
+            looping:            self.looping,
+            sort:               self.sort,
+            offscreen:          self.offscreen,
+            lod:                &self.lod,
+            material:           self.material,
+            bounds:             self.bounds,
+        }
+    }
+
+    /// Create the system along with its forces, curves, expressions and bindings.
+    #[cfg(feature = "wgpu")]
+    pub fn create(&self, device: &wgpu::Device) -> BrumousResult<ParticleSystem> {
+        let mut sys = ParticleSystem::new(device, &self.descriptor())?;
+        self.add_effects(&mut sys);
+        Ok(sys)
+    }
+
+    /// Create the system without GPU buffers, see [`ParticleSystem::headless`].
+    pub fn create_headless(&self) -> BrumousResult<ParticleSystem> {
+        let mut sys = ParticleSystem::headless(&self.descriptor())?;
+        self.add_effects(&mut sys);
+        Ok(sys)
+    }
+
+    /// Add forces, attractors, colliders, curves, expressions, parameters
+    /// and bindings of the descriptor to a system.
+    pub fn add_effects(&self, sys: &mut ParticleSystem) {
+        for &force in self.forces.iter() {
+            sys.add_force(force);
+        }
+        for att in self.attractors.iter() {
+            sys.add_attractor(att.position, att.mass);
+        }
+        for collider in self.colliders.iter() {
+            match *collider {
+                EffectCollider::Plane { point, normal, restitution } => {
+                    sys.add_plane_collider(point, normal, restitution);
+                }
+                EffectCollider::Sphere { center, radius, restitution } => {
+                    sys.add_sphere_collider(center, radius, restitution);
+                }
+            }
+        }
+        if let Some(curve) = &self.color_over_life {
+            sys.add_animation(ParticleAnimation::ColorOverLife(curve.clone()));
+        }
+        if let Some(curve) = &self.scale_over_life {
+            sys.add_animation(ParticleAnimation::ScaleOverLife(curve.clone()));
+        }
+        for (channel, expr) in self.spawn.iter() {
+            sys.add_spawn_expr(*channel, expr.clone());
+        }
+        for (channel, expr) in self.animations.iter() {
+            sys.add_animation(ParticleAnimation::Expr(*channel, expr.clone()));
+        }
+        for (name, value) in self.parameters.iter() {
+            sys.declare_parameter(name, *value);
+        }
+        for (binding, expr) in self.bindings.iter() {
+            sys.add_binding(*binding, expr.clone());
+        }
+    }
+}
+impl Default for EffectSystemDescriptor {
+    fn default() -> Self {
+        let desc = ParticleSystemDescriptor::default();
+        Self {
+            name:               desc.name.to_string(),
+            max:                desc.max,
+            rate:               desc.rate,
+            rate_over_distance: desc.rate_over_distance,
+            position:           desc.pos.into(),
+            rotation:           desc.rotation.into(),
+            scale:              desc.scale.into(),
+            space:              desc.space,
+            inherit_velocity:   desc.inherit_velocity,
+            life:               desc.life,
+            looping:            desc.looping,
+            sort:               desc.sort,
+            offscreen:          desc.offscreen,
+            lod:                Vec::new(),
+            material:           desc.material,
+            bounds:             desc.bounds,
+            forces:             Vec::new(),
+            attractors:         Vec::new(),
+            colliders:          Vec::new(),
+            color_over_life:    None,
+            scale_over_life:    None,
+            spawn:              Vec::new(),
+            animations:         Vec::new(),
+            parameters:         Vec::new(),
+            bindings:           Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EffectAttractor {
+    pub position: [f32; 3],
+    pub mass:     f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum EffectCollider {
+    Plane {
+        point:       [f32; 3],
+        normal:      [f32; 3],
+        restitution: f32,
+    },
+    Sphere {
+        center:      [f32; 3],
+        radius:      f32,
+        restitution: f32,
+    },
+}
+
+/// Settings of the renderer drawing an effect.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EffectRendererDescriptor {
+    pub texture:       Option<String>,
+    pub mesh:          EffectMesh,
+    pub max_lights:    usize,
+    pub depth_texture: Option<EffectDepthFormat>,
+    pub shader:        Option<String>,
+    pub attributes:    u32,
+    pub lod_meshes:    Vec<EffectMesh>,
+}
+impl Default for EffectRendererDescriptor {
+    fn default() -> Self {
+        Self {
+            texture:       None,
+            mesh:          EffectMesh::default(),
+            max_lights:    DEFAULT_MAX_LIGHTS,
+            depth_texture: None,
+            shader:        None,
+            attributes:    0,
+            lod_meshes:    Vec::new(),
+        }
+    }
+}
+#[cfg(feature = "wgpu")]
+impl EffectRendererDescriptor {
+    /// Return mesh types of the LOD meshes, to pass to [`EffectRendererDescriptor::descriptor`].
+    pub fn lod_mesh_types(&self) -> Vec<ParticleMeshType<'_>> {
+        self.lod_meshes.iter().map(|mesh| mesh.mesh_type()).collect()
+    }
+
+    /// Return descriptor of the renderer, borrowing strings and LOD meshes.
+    pub fn descriptor<'a>(&'a self, lod_meshes: &'a [ParticleMeshType<'a>]) -> ParticleSystemRendererDescriptor<'a> {
+        ParticleSystemRendererDescriptor {
+            texture:       self.texture.as_deref(),
+            mesh_type:     self.mesh.mesh_type(),
+            max_lights:    self.max_lights,
+            depth_texture: self.depth_texture.map(|format| DepthTextureDescriptor {
+                texture_format: format.into(),
+            }),
+            shader:        self.shader.as_deref(),
+            attributes:    self.attributes,
+            lod_meshes,
+        }
+    }
+}
+
+/// Model of each particle, see [`ParticleMeshType`].
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum EffectMesh {
+    #[default]
+    Cube,
+    Point,
+    Custom(String),
+}
+#[cfg(feature = "wgpu")]
+impl EffectMesh {
+    fn mesh_type(&self) -> ParticleMeshType<'_> {
+        match self {
+            EffectMesh::Cube => ParticleMeshType::Cube,
+            EffectMesh::Point => ParticleMeshType::Point,
+            EffectMesh::Custom(path) => ParticleMeshType::Custom(path),
+        }
+    }
+}
+
+/// Depth texture formats an effect can be rendered with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EffectDepthFormat {
+    Depth32Float,
+    Depth24Plus,
+    Depth24PlusStencil8,
+}
+#[cfg(feature = "wgpu")]
+impl From<EffectDepthFormat> for wgpu::TextureFormat {
+    fn from(format: EffectDepthFormat) -> Self {
+        match format {
+            EffectDepthFormat::Depth32Float => Self::Depth32Float,
+            EffectDepthFormat::Depth24Plus => Self::Depth24Plus,
+            EffectDepthFormat::Depth24PlusStencil8 => Self::Depth24PlusStencil8,
+        }
+    }
+}
+
+
+#[test]
+fn effect_round_trip() {
+    let data = r#"(
+        systems: [
+            (
+                name: "sparks",
+                rate: 20,
+                position: (0.0, 1.0, 0.0),
+                forces: [(0.0, -9.8, 0.0)],
+                colliders: [Plane(point: (0.0, 0.0, 0.0), normal: (0.0, 1.0, 0.0), restitution: 0.4)],
+                scale_over_life: Some([(1.0, 0.0), (0.0, 0.01)]),
+                spawn: [(Life, "lerp(1, 2, random)")],
+                animations: [(Alpha, "1 - t^2")],
+                parameters: [("intensity", Float(1.0)), ("wind", Vec3((1.0, 0.0, 0.0)))],
+                bindings: [(Rate, "20 * intensity"), (ForceStrength(0), "wind.x")],
+            ),
+            (name: "smoke", sort: BackToFront, lod: [(distance: 50.0, rate: 0.5, max: 0.5, interval: 2, mesh: None)]),
+        ],
+        renderer: (mesh: Point, depth_texture: Some(Depth32Float)),
+    )"#;
+    let effect = EffectDescriptor::from_ron(data).unwrap();
+    assert!(effect.systems.len() == 2);
+    assert!(effect.systems[0].rate == 20);
+    assert!(effect.systems[1].max == ParticleSystemDescriptor::default().max);
+    assert!(effect.renderer.mesh == EffectMesh::Point);
+
+    let saved = effect.to_ron().unwrap();
+    assert!(EffectDescriptor::from_ron(&saved).unwrap() == effect);
+    assert!(EffectDescriptor::from_ron("(systems: 1)").is_err());
+    assert!(EffectDescriptor::from_ron("(systems: [(animations: [(Alpha, \"1 +\")])])").is_err());
+}
+
+#[test]
+fn reload_headless() {
+    use std::time::{Duration, SystemTime};
+
+    let path = std::env::temp_dir().join(format!("brumous_effect_{}.ron", std::process::id()));
+    // Saves within the file system's timestamp resolution keep the same time.
+    let write = |data: &str, secs: u64| {
+        fs::write(&path, data).unwrap();
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(secs)).unwrap();
+    };
+    write(r#"(systems: [(name: "a", rate: 2), (name: "b", rate: 1)])"#, 1000);
+
+    let mut file = EffectFile::load(&path).unwrap();
+    let mut set = file.effect().create_headless_systems().unwrap();
+    for _ in 0..3 {
+        set.simulate(Duration::from_millis(16), [0.0; 3]);
+    }
+    assert!(!file.poll_changes_headless(&mut set).unwrap());
+
+    // A new system with rate above max fails before anything is changed.
+    write(r#"(systems: [(name: "a", rate: 4), (name: "c", max: 1, rate: 2)])"#, 2000);
+    assert!(file.poll_changes_headless(&mut set).is_err());
+    assert!(set.len() == 2);
+    assert!(file.poll_changes_headless(&mut set).is_err());
+
+    // Caught mid-save, then finished with the same time.
+    write(r#"(systems: [(name: "a","#, 3000);
+    assert!(file.poll_changes_headless(&mut set).is_err());
+    write(r#"(systems: [(name: "a", rate: 4)])"#, 3000);
+    assert!(file.poll_changes_headless(&mut set).unwrap());
+    assert!(!file.poll_changes_headless(&mut set).unwrap());
+
+    assert!(set.len() == 1 && set.get("b").is_none());
+    assert!(set.systems()[0].alive_count() == 6);
+    set.simulate(Duration::from_millis(16), [0.0; 3]);
+    assert!(set.systems()[0].alive_count() == 10);
+    fs::remove_file(&path).unwrap();
+}
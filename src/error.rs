@@ -1,6 +1,5 @@
 use std::io;
 use std::fmt;
-use image::error::ImageError;
 
 pub type BrumousResult<T> = Result<T, BrumousError>;
 
@@ -11,8 +10,19 @@ pub enum BrumousError {
     ParseFloat(String, usize),
     InvalidVertexData(String, usize),
     OpenTexture(String, io::Error),
-    LoadTexture(String, ImageError),
+    LoadTexture(String, String),
     InvalidLightIndex(u64, u64),
+    ParseEffect(String, String),
+    SaveEffect(String),
+    ParseExpr(String, usize, String),
+    ZeroMaxParticles(String),
+    RateExceedsMax(String, usize, usize),
+    NegativeLife(String, f32),
+    NegativeParticleLife(String, f32, f32),
+    NaNBounds(String, String),
+    NaNLodDistance(String, usize),
+    InvalidLodBand(String, usize, String),
+    ZeroMass(String, f32, f32),
 }
 
 impl From<io::Error> for BrumousError {
@@ -62,6 +72,67 @@ impl fmt::Display for BrumousError {
                     \rwhich exceeds the maximum of {max}",
                 )
             }
+            BrumousError::ParseEffect(path, err) => {
+                write!(f, "
+                    \rError parsing effect {path}:
+                    \r{err}",
+                )
+            }
+            BrumousError::SaveEffect(err) => {
+                write!(f, "
+                    \rError saving effect:
+                    \r{err}",
+                )
+            }
+            BrumousError::ParseExpr(expr, col, err) => {
+                write!(f, "
+                    \rError parsing expression \"{expr}\" at column {col}:
+                    \r{err}",
+                )
+            }
+            BrumousError::ZeroMaxParticles(name) => {
+                write!(f, "
+                    \rParticle system {name} has a max of 0 particles",
+                )
+            }
+            BrumousError::RateExceedsMax(name, rate, max) => {
+                write!(f, "
+                    \rParticle system {name} has a rate of {rate}, 
+                    \rwhich exceeds its max of {max} particles",
+                )
+            }
+            BrumousError::NegativeLife(name, life) => {
+                write!(f, "
+                    \rParticle system {name} has a negative life of {life}",
+                )
+            }
+            BrumousError::NegativeParticleLife(name, mean, var) => {
+                write!(f, "
+                    \rParticle system {name} has life bounds ({mean}, {var}), 
+                    \rwhich allow particles with negative lifetimes",
+                )
+            }
+            BrumousError::NaNBounds(name, field) => {
+                write!(f, "
+                    \rParticle system {name} has NaN in its {field} bounds",
+                )
+            }
+            BrumousError::NaNLodDistance(name, idx) => {
+                write!(f, "
+                    \rParticle system {name} has NaN as the distance of LOD band {idx}",
+                )
+            }
+            BrumousError::InvalidLodBand(name, idx, field) => {
+                write!(f, "
+                    \rParticle system {name} has an invalid {field} in LOD band {idx}",
+                )
+            }
+            BrumousError::ZeroMass(name, mean, var) => {
+                write!(f, "
+                    \rParticle system {name} has mass bounds ({mean}, {var}), 
+                    \rwhich allow particles with zero or negative mass",
+                )
+            }
         }
     }
 }
@@ -0,0 +1,568 @@
+use std::fmt;
+
+use crate::error::{BrumousError, BrumousResult};
+
+/// Max depth of the evaluation stack, deeper expressions fail to compile.
+const MAX_STACK: usize = 32;
+/// Max nesting of parentheses, calls, signs and exponents while parsing,
+/// which keeps the recursive parser from overflowing the stack.
+const MAX_NESTING: usize = 256;
+
+/// Arithmetic expression evaluated for every particle, e.g. `1 - t^2`
+/// or `lerp(0.2, 0.5, random) * intensity`.
+///
+/// Expressions support `+ - * / % ^`, parentheses, the constants `pi` and
+/// `tau`, and these functions:
+///
+/// - `sin cos tan asin acos atan sqrt abs floor ceil round fract exp ln log2 log10 sign saturate`
+/// - `min max pow atan2 step`
+/// - `clamp lerp smoothstep`
+///
+/// Variables are the particle's `age` and remaining `life` in seconds,
+/// `t` from 0 at spawn to 1 at death, `speed`, `random` in [0, 1) and the
+/// system's `time` in seconds. In animations `random` is fixed per
+/// particle. Any other name is a named parameter of the system, which is 0
+/// until it is set.
+///
+/// Expressions are compiled once, with constant parts folded, to a list of
+/// operations on a small stack.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "String", into = "String"))]
+pub struct Expr {
+    source: String,
+    ops:    Vec<Op>,
+    params: Vec<String>,
+}
+impl Expr {
+    pub fn new(source: &str) -> BrumousResult<Self> {
+        let mut parser = Parser {
+            source,
+            tokens: tokenize(source)?,
+            pos:    0,
+            depth:  0,
+            params: Vec::new(),
+        };
+        let node = parser.additive()?;
+        if let Some(&(col, _)) = parser.tokens.get(parser.pos) {
+            return Err(parse_error(source, col, "unexpected token"));
+        }
+
+        let mut ops = Vec::new();
+        let depth = compile(&node, &mut ops);
+        if depth > MAX_STACK {
+            return Err(parse_error(source, 0, "expression is too deeply nested"));
+        }
+        Ok(
+            Self {
+                source: source.to_string(),
+                ops,
+                params: parser.params,
+            }
+        )
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Return names of parameters used by the expression. Values passed to
+    /// [`Expr::eval`] are in the same order.
+    pub fn params(&self) -> &[String] {
+        &self.params
+    }
+
+    /// Return value of the expression. Missing parameters are 0.
+    pub fn eval(&self, vars: &ExprVars, params: &[f32]) -> f32 {
+        let mut stack = [0.0f32; MAX_STACK];
+        let mut top = 0;
+        for op in self.ops.iter() {
+            match *op {
+                Op::Const(x) => {
+                    stack[top] = x;
+                    top += 1;
+                }
+                Op::Var(var) => {
+                    stack[top] = vars.get(var);
+                    top += 1;
+                }
+                Op::Param(i) => {
+                    stack[top] = params.get(i).copied().unwrap_or(0.0);
+                    top += 1;
+                }
+                Op::Unary(f) => {
+                    stack[top - 1] = f(stack[top - 1]);
+                }
+                Op::Binary(f) => {
+                    top -= 1;
+                    stack[top - 1] = f(stack[top - 1], stack[top]);
+                }
+                Op::Ternary(f) => {
+                    top -= 2;
+                    stack[top - 1] = f(stack[top - 1], stack[top], stack[top + 1]);
+                }
+            }
+        }
+        stack[0]
+    }
+}
+impl PartialEq for Expr {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+impl fmt::Debug for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Expr({:?})", self.source)
+    }
+}
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+impl TryFrom<String> for Expr {
+    type Error = BrumousError;
+
+    fn try_from(source: String) -> BrumousResult<Self> {
+        Self::new(&source)
+    }
+}
+impl From<Expr> for String {
+    fn from(expr: Expr) -> Self {
+        expr.source
+    }
+}
+
+/// Values of the variables an expression can use.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ExprVars {
+    pub age:    f32,
+    pub life:   f32,
+    pub speed:  f32,
+    pub random: f32,
+    pub time:   f32,
+}
+impl ExprVars {
+    fn get(&self, var: Var) -> f32 {
+        match var {
+            Var::Age => self.age,
+            Var::Life => self.life,
+            Var::T => crate::particle::life_fraction(self.age, self.life),
+            Var::Speed => self.speed,
+            Var::Random => self.random,
+            Var::Time => self.time,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Var {
+    Age,
+    Life,
+    T,
+    Speed,
+    Random,
+    Time,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Op {
+    Const(f32),
+    Var(Var),
+    Param(usize),
+    Unary(fn(f32) -> f32),
+    Binary(fn(f32, f32) -> f32),
+    Ternary(fn(f32, f32, f32) -> f32),
+}
+
+enum Node {
+    Const(f32),
+    Var(Var),
+    Param(usize),
+    Unary(fn(f32) -> f32, Box<Node>),
+    Binary(fn(f32, f32) -> f32, Box<Node>, Box<Node>),
+    Ternary(fn(f32, f32, f32) -> f32, Box<Node>, Box<Node>, Box<Node>),
+}
+impl Node {
+    fn unary(f: fn(f32) -> f32, a: Node) -> Self {
+        match a {
+            Node::Const(a) => Node::Const(f(a)),
+            a => Node::Unary(f, Box::new(a)),
+        }
+    }
+
+    fn binary(f: fn(f32, f32) -> f32, a: Node, b: Node) -> Self {
+        match (a, b) {
+            (Node::Const(a), Node::Const(b)) => Node::Const(f(a, b)),
+            (a, b) => Node::Binary(f, Box::new(a), Box::new(b)),
+        }
+    }
+
+    fn ternary(f: fn(f32, f32, f32) -> f32, a: Node, b: Node, c: Node) -> Self {
+        match (a, b, c) {
+            (Node::Const(a), Node::Const(b), Node::Const(c)) => Node::Const(f(a, b, c)),
+            (a, b, c) => Node::Ternary(f, Box::new(a), Box::new(b), Box::new(c)),
+        }
+    }
+}
+
+/// Push operations evaluating node, returning the stack depth needed.
+fn compile(node: &Node, ops: &mut Vec<Op>) -> usize {
+    match node {
+        Node::Const(x) => {
+            ops.push(Op::Const(*x));
+            1
+        }
+        Node::Var(var) => {
+            ops.push(Op::Var(*var));
+            1
+        }
+        Node::Param(i) => {
+            ops.push(Op::Param(*i));
+            1
+        }
+        Node::Unary(f, a) => {
+            let depth = compile(a, ops);
+            ops.push(Op::Unary(*f));
+            depth
+        }
+        Node::Binary(f, a, b) => {
+            let depth = compile(a, ops).max(1 + compile(b, ops));
+            ops.push(Op::Binary(*f));
+            depth
+        }
+        Node::Ternary(f, a, b, c) => {
+            let depth = compile(a, ops)
+                .max(1 + compile(b, ops))
+                .max(2 + compile(c, ops));
+            ops.push(Op::Ternary(*f));
+            depth
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Num(f32),
+    Ident(String),
+    Op(char),
+}
+
+/// Split source into tokens, along with the column each token starts at.
+fn tokenize(source: &str) -> BrumousResult<Vec<(usize, Token)>> {
+    let chars = source.chars().collect::<Vec<char>>();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        if c.is_whitespace() {
+            i += 1;
+        }
+        else if c.is_ascii_digit() || c == '.' {
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            // Exponent, e.g. 1e-3.
+            if i < chars.len() && (chars[i] == 'e' || chars[i] == 'E') {
+                let sign = chars.get(i + 1).is_some_and(|&c| c == '-' || c == '+');
+                let digit = if sign { i + 2 } else { i + 1 };
+                if chars.get(digit).is_some_and(|c| c.is_ascii_digit()) {
+                    i = digit;
+                    while i < chars.len() && chars[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                }
+            }
+            let text = chars[start..i].iter().collect::<String>();
+            let num = text.parse::<f32>()
+                .map_err(|_| parse_error(source, start, "invalid number"))?;
+            tokens.push((start, Token::Num(num)));
+        }
+        else if c.is_alphabetic() || c == '_' {
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.') {
+                i += 1;
+            }
+            tokens.push((start, Token::Ident(chars[start..i].iter().collect())));
+        }
+        else if "+-*/%^(),".contains(c) {
+            tokens.push((start, Token::Op(c)));
+            i += 1;
+        }
+        else {
+            return Err(parse_error(source, start, "unexpected character"));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<(usize, Token)>,
+    pos:    usize,
+    /// Number of nested calls to unary, which every recursion goes through.
+    depth:  usize,
+    params: Vec<String>,
+}
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, token)| token)
+    }
+
+    /// Return column of the current token, or the end of the source.
+    fn col(&self) -> usize {
+        self.tokens.get(self.pos).map_or(self.source.chars().count(), |&(col, _)| col)
+    }
+
+    fn eat(&mut self, op: char) -> bool {
+        if self.peek() == Some(&Token::Op(op)) {
+            self.pos += 1;
+            true
+        }
+        else {
+            false
+        }
+    }
+
+    fn expect(&mut self, op: char) -> BrumousResult<()> {
+        if self.eat(op) {
+            Ok(())
+        }
+        else {
+            Err(parse_error(self.source, self.col(), &format!("expected '{op}'")))
+        }
+    }
+
+    fn additive(&mut self) -> BrumousResult<Node> {
+        let mut node = self.multiplicative()?;
+        loop {
+            if self.eat('+') {
+                node = Node::binary(|a, b| a + b, node, self.multiplicative()?);
+            }
+            else if self.eat('-') {
+                node = Node::binary(|a, b| a - b, node, self.multiplicative()?);
+            }
+            else {
+                return Ok(node);
+            }
+        }
+    }
+
+    fn multiplicative(&mut self) -> BrumousResult<Node> {
+        let mut node = self.unary()?;
+        loop {
+            if self.eat('*') {
+                node = Node::binary(|a, b| a * b, node, self.unary()?);
+            }
+            else if self.eat('/') {
+                node = Node::binary(|a, b| a / b, node, self.unary()?);
+            }
+            else if self.eat('%') {
+                node = Node::binary(|a, b| a.rem_euclid(b), node, self.unary()?);
+            }
+            else {
+                return Ok(node);
+            }
+        }
+    }
+
+    fn unary(&mut self) -> BrumousResult<Node> {
+        if self.depth == MAX_NESTING {
+            return Err(parse_error(self.source, self.col(), "expression is too deeply nested"));
+        }
+        self.depth += 1;
+        let node = self.sign();
+        self.depth -= 1;
+        node
+    }
+
+    fn sign(&mut self) -> BrumousResult<Node> {
+        if self.eat('-') {
+            Ok(Node::unary(|a| -a, self.unary()?))
+        }
+        else if self.eat('+') {
+            self.unary()
+        }
+        else {
+            self.power()
+        }
+    }
+
+    /// Exponents are right associative and bind tighter than negation,
+    /// so -2^2 is -4.
+    fn power(&mut self) -> BrumousResult<Node> {
+        let base = self.primary()?;
+        if self.eat('^') {
+            Ok(Node::binary(f32::powf, base, self.unary()?))
+        }
+        else {
+            Ok(base)
+        }
+    }
+
+    fn primary(&mut self) -> BrumousResult<Node> {
+        let col = self.col();
+        match self.peek().cloned() {
+            Some(Token::Num(x)) => {
+                self.pos += 1;
+                Ok(Node::Const(x))
+            }
+            Some(Token::Op('(')) => {
+                self.pos += 1;
+                let node = self.additive()?;
+                self.expect(')')?;
+                Ok(node)
+            }
+            Some(Token::Ident(name)) => {
+                self.pos += 1;
+                if self.eat('(') {
+                    self.call(&name, col)
+                }
+                else {
+                    Ok(self.name(name))
+                }
+            }
+            Some(_) => Err(parse_error(self.source, col, "unexpected token")),
+            None => Err(parse_error(self.source, col, "unexpected end of expression")),
+        }
+    }
+
+    fn name(&mut self, name: String) -> Node {
+        match name.as_str() {
+            "age" => Node::Var(Var::Age),
+            "life" => Node::Var(Var::Life),
+            "t" => Node::Var(Var::T),
+            "speed" => Node::Var(Var::Speed),
+            "random" => Node::Var(Var::Random),
+            "time" => Node::Var(Var::Time),
+            "pi" => Node::Const(std::f32::consts::PI),
+            "tau" => Node::Const(std::f32::consts::TAU),
+            _ => {
+                let idx = match self.params.iter().position(|param| *param == name) {
+                    Some(idx) => idx,
+                    None => {
+                        self.params.push(name);
+                        self.params.len() - 1
+                    }
+                };
+                Node::Param(idx)
+            }
+        }
+    }
+
+    /// Parse arguments of a function call, after the opening parenthesis.
+    fn call(&mut self, name: &str, col: usize) -> BrumousResult<Node> {
+        let mut args = Vec::new();
+        if !self.eat(')') {
+            loop {
+                args.push(self.additive()?);
+                if self.eat(')') {
+                    break;
+                }
+                self.expect(',')?;
+            }
+        }
+
+        let unary: Option<fn(f32) -> f32> = match name {
+            "sin" => Some(f32::sin),
+            "cos" => Some(f32::cos),
+            "tan" => Some(f32::tan),
+            "asin" => Some(f32::asin),
+            "acos" => Some(f32::acos),
+            "atan" => Some(f32::atan),
+            "sqrt" => Some(f32::sqrt),
+            "abs" => Some(f32::abs),
+            "floor" => Some(f32::floor),
+            "ceil" => Some(f32::ceil),
+            "round" => Some(f32::round),
+            "fract" => Some(|a| a - a.floor()),
+            "exp" => Some(f32::exp),
+            "ln" => Some(f32::ln),
+            "log2" => Some(f32::log2),
+            "log10" => Some(f32::log10),
+            "sign" => Some(|a| if a == 0.0 { 0.0 } else { a.signum() }),
+            "saturate" => Some(|a| a.clamp(0.0, 1.0)),
+            _ => None,
+        };
+        let binary: Option<fn(f32, f32) -> f32> = match name {
+            "min" => Some(f32::min),
+            "max" => Some(f32::max),
+            "pow" => Some(f32::powf),
+            "atan2" => Some(f32::atan2),
+            "step" => Some(|edge, a| if a < edge { 0.0 } else { 1.0 }),
+            _ => None,
+        };
+        let ternary: Option<fn(f32, f32, f32) -> f32> = match name {
+            "clamp" => Some(|a, lo, hi| a.max(lo).min(hi)),
+            "lerp" => Some(|a, b, t| a + (b - a) * t),
+            "smoothstep" => Some(|lo, hi, a| {
+                let t = ((a - lo) / (hi - lo)).clamp(0.0, 1.0);
+                t * t * (3.0 - 2.0 * t)
+            }),
+            _ => None,
+        };
+
+        let expected = if unary.is_some() {
+            1
+        }
+        else if binary.is_some() {
+            2
+        }
+        else if ternary.is_some() {
+            3
+        }
+        else {
+            return Err(parse_error(self.source, col, &format!("unknown function '{name}'")));
+        };
+        if args.len() != expected {
+            return Err(
+                parse_error(self.source, col, &format!("'{name}' takes {expected} arguments"))
+            );
+        }
+
+        let mut args = args.into_iter();
+        let mut arg = || args.next().unwrap();
+        Ok(
+            match (unary, binary, ternary) {
+                (Some(f), _, _) => Node::unary(f, arg()),
+                (_, Some(f), _) => Node::binary(f, arg(), arg()),
+                (_, _, Some(f)) => Node::ternary(f, arg(), arg(), arg()),
+                _ => unreachable!(),
+            }
+        )
+    }
+}
+
+fn parse_error(source: &str, col: usize, msg: &str) -> BrumousError {
+    BrumousError::ParseExpr(source.to_string(), col, msg.to_string())
+}
+
+
+#[test]
+fn eval_expr() {
+    let vars = ExprVars { age: 1.0, life: 3.0, speed: 2.0, random: 0.5, time: 10.0 };
+    let eval = |source: &str, params: &[f32]| Expr::new(source).unwrap().eval(&vars, params);
+
+    assert!(eval("1 + 2 * 3 - 4 / 2", &[]) == 5.0);
+    assert!(eval("-2^2 + 2^3^0", &[]) == -2.0);
+    assert!(eval("(1 + 2) * 3 % 4", &[]) == 1.0);
+    assert!(eval("clamp(speed * 10, 0, 5) + max(1e1, 2) + saturate(-3)", &[]) == 15.0);
+    assert!(eval("t + age + life + random + time", &[]) == 14.75);
+    assert!(eval("lerp(0, intensity, t) + wind.x", &[8.0, 1.0]) == 3.0);
+    assert!(eval("missing", &[]) == 0.0);
+
+    let expr = Expr::new("sin(pi / 2) * scale + scale").unwrap();
+    assert!(expr.params() == ["scale"]);
+    // The constant sin(pi / 2) is folded.
+    assert!(expr.ops.len() == 5);
+
+    assert!(Expr::new("1 +").is_err());
+    assert!(Expr::new("(1").is_err());
+    assert!(Expr::new("foo(1)").is_err());
+    assert!(Expr::new("min(1)").is_err());
+    assert!(Expr::new("1 $ 2").is_err());
+    assert!(Expr::new("1 2").is_err());
+    assert!(Expr::new(&format!("{}1{}", "(".repeat(100_000), ")".repeat(100_000))).is_err());
+    assert!(Expr::new(&format!("{}1", "-".repeat(100_000))).is_err());
+}
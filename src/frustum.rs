@@ -0,0 +1,80 @@
+use crate::particle_system::Aabb;
+
+/// View frustum as six planes, used to cull particle systems that are off screen.
+///
+/// Each plane is [a, b, c, d] with the normal pointing into the frustum,
+/// so a point p is inside when a*p.x + b*p.y + c*p.z + d >= 0.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Frustum {
+    pub planes: [[f32; 4]; 6],
+}
+impl Frustum {
+    pub fn from_planes(planes: [[f32; 4]; 6]) -> Self {
+        Self { planes }
+    }
+
+    /// Extract frustum planes from a column major view-projection matrix,
+    /// with clip space depth in the range 0 to 1 as used by wgpu.
+    pub fn from_view_proj(m: [[f32; 4]; 4]) -> Self {
+        let row = |i: usize| [m[0][i], m[1][i], m[2][i], m[3][i]];
+        let (r0, r1, r2, r3) = (row(0), row(1), row(2), row(3));
+        let add = |a: [f32; 4], b: [f32; 4]| [a[0] + b[0], a[1] + b[1], a[2] + b[2], a[3] + b[3]];
+        let sub = |a: [f32; 4], b: [f32; 4]| [a[0] - b[0], a[1] - b[1], a[2] - b[2], a[3] - b[3]];
+
+        Self {
+            planes: [
+                add(r3, r0), // Left
+                sub(r3, r0), // Right
+                add(r3, r1), // Bottom
+                sub(r3, r1), // Top
+                r2,          // Near
+                sub(r3, r2), // Far
+            ]
+        }
+    }
+
+    /// Return false only if the box is entirely outside one of the planes.
+    /// Boxes near the corners of the frustum may be reported as visible.
+    pub fn intersects(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|plane| {
+            // Corner of the box furthest along the plane normal.
+            let dist = (0..3)
+                .map(|i| plane[i] * if plane[i] >= 0.0 { aabb.max[i] } else { aabb.min[i] })
+                .sum::<f32>();
+            dist + plane[3] >= 0.0
+        })
+    }
+}
+
+/// What a particle system does while it is outside the view frustum.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OffscreenMode {
+    /// Keep simulating, skip uploading and drawing.
+    #[default]
+    Simulate,
+    /// Freeze simulation until the system is visible again.
+    Pause,
+}
+
+
+#[test]
+fn frustum_culling() {
+    let identity = [
+        [1.0, 0.0, 0.0, 0.0],
+        [0.0, 1.0, 0.0, 0.0],
+        [0.0, 0.0, 1.0, 0.0],
+        [0.0, 0.0, 0.0, 1.0],
+    ];
+    let frustum = Frustum::from_view_proj(identity);
+
+    let inside = Aabb { min: [-0.5, -0.5, 0.2], max: [0.5, 0.5, 0.8] };
+    let straddling = Aabb { min: [0.5, 0.5, 0.5], max: [2.0, 2.0, 0.6] };
+    let right = Aabb { min: [1.5, 0.0, 0.5], max: [2.0, 0.5, 0.6] };
+    let behind = Aabb { min: [0.0, 0.0, -2.0], max: [0.5, 0.5, -1.0] };
+
+    assert!(frustum.intersects(&inside));
+    assert!(frustum.intersects(&straddling));
+    assert!(!frustum.intersects(&right));
+    assert!(!frustum.intersects(&behind));
+}
@@ -0,0 +1,554 @@
+use std::mem;
+use std::time::Duration;
+use std::collections::VecDeque;
+
+use crate::particle::{Particle, ParticleInstance};
+use crate::particle_system::{ParticleAttractor, ParticleView};
+use crate::random::Randf32;
+use crate::error::BrumousResult;
+use crate::transform::Transform;
+use crate::quaternion::Quaternion;
+use crate::vector::{Vec3, Vec4};
+use crate::ParticleSystemDescriptor;
+use crate::ParticleSystemBounds;
+
+use wgpu::util::DeviceExt;
+
+const SHADER: &str = include_str!("particle_compute.wgsl");
+const WORKGROUP_SIZE: u32 = 64;
+
+
+/// A particle system simulated in compute shaders.
+///
+/// Particle state lives in storage buffers on the GPU and the instance
+/// buffer is written directly by the compute pass, so nothing but newly
+/// spawned particles is uploaded each frame. New particles are generated
+/// on the CPU with the same random sequence as [`ParticleSystem`], which
+/// keeps both paths in step. Particles are not sorted, and colliders,
+/// animations, attributes and events are only supported by the CPU path.
+///
+/// Of the descriptor, `rate_over_distance`, `space`, `inherit_velocity`,
+/// `sort`, `offscreen` and `lod` are ignored: particles are always
+/// simulated in world space, emitted at a fixed rate and drawn unsorted at
+/// full detail. There are no time scales either, so `update` always
+/// simulates the delta it is given.
+///
+/// [`ParticleSystem`]: crate::particle_system::ParticleSystem
+pub struct GpuParticleSystem {
+    state_buf:      wgpu::Buffer,
+    spawn_buf:      wgpu::Buffer,
+    attractor_buf:  wgpu::Buffer,
+    instance_buf:   wgpu::Buffer,
+    params_buf:     wgpu::Buffer,
+    bind_layout:    wgpu::BindGroupLayout,
+    bind_group:     wgpu::BindGroup,
+    spawn_pipeline: wgpu::ComputePipeline,
+    update_pipeline: wgpu::ComputePipeline,
+    max:            usize,
+    rate:           usize,
+    material:       u32,
+    transform:      Transform,
+    name:           String,
+    life:           f32,
+    duration:       f32,
+    looping:        bool,
+    bounds:         ParticleSystemBounds,
+    forces:         Vec<Vec3>,
+    attractors:     Vec<ParticleAttractor>,
+    attractor_cap:  usize,
+    rand:           Randf32,
+    slot_life:      Vec<f32>,
+    free:           VecDeque<usize>,
+    spawns:         Vec<GpuParticle>,
+}
+impl GpuParticleSystem {
+    pub fn new(
+        device: &wgpu::Device,
+        sys_desc: &ParticleSystemDescriptor,
+    ) -> BrumousResult<Self> {
+        sys_desc.validate()?;
+        let max = sys_desc.max;
+
+        let state_buf = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("GPU Particle Buffer"),
+                contents: bytemuck::cast_slice(&vec![GpuParticle::default(); max]),
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            }
+        );
+        let spawn_buf = device.create_buffer(
+            &wgpu::BufferDescriptor {
+                label: Some("GPU Spawn Buffer"),
+                size: max as u64 * GpuParticle::size(),
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }
+        );
+        let instance_buf = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("GPU Instance Buffer"),
+                contents: bytemuck::cast_slice(&vec![ParticleInstance::default(); max]),
+                usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            }
+        );
+        let params_buf = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("GPU Particle Params Buffer"),
+                contents: bytemuck::cast_slice(&[GpuParams::default()]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            }
+        );
+        let attractor_buf = create_attractor_buf(device, 1);
+
+        let bind_layout = device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor {
+                label: Some("GPU Particle Bind Group Layout"),
+                entries: &[
+                    layout_entry(0, wgpu::BufferBindingType::Uniform),
+                    layout_entry(1, wgpu::BufferBindingType::Storage { read_only: false }),
+                    layout_entry(2, wgpu::BufferBindingType::Storage { read_only: true }),
+                    layout_entry(3, wgpu::BufferBindingType::Storage { read_only: true }),
+                    layout_entry(4, wgpu::BufferBindingType::Storage { read_only: false }),
+                ]
+            }
+        );
+        let bind_group = create_bind_group(
+            device,
+            &bind_layout,
+            [&params_buf, &state_buf, &spawn_buf, &attractor_buf, &instance_buf],
+        );
+
+        let shader = device.create_shader_module(
+            wgpu::ShaderModuleDescriptor {
+                label: Some("Compute Shader"),
+                source: wgpu::ShaderSource::Wgsl(SHADER.into()),
+            }
+        );
+        let pipeline_layout = device.create_pipeline_layout(
+            &wgpu::PipelineLayoutDescriptor {
+                label: Some("Compute Pipeline Layout"),
+                bind_group_layouts: &[&bind_layout],
+                push_constant_ranges: &[],
+            }
+        );
+        let spawn_pipeline = device.create_compute_pipeline(
+            &wgpu::ComputePipelineDescriptor {
+                label: Some("Spawn Pipeline"),
+                layout: Some(&pipeline_layout),
+                module: &shader,
+                entry_point: "cs_spawn",
+            }
+        );
+        let update_pipeline = device.create_compute_pipeline(
+            &wgpu::ComputePipelineDescriptor {
+                label: Some("Update Pipeline"),
+                layout: Some(&pipeline_layout),
+                module: &shader,
+                entry_point: "cs_update",
+            }
+        );
+
+        Ok(
+            Self {
+                state_buf,
+                spawn_buf,
+                attractor_buf,
+                instance_buf,
+                params_buf,
+                bind_layout,
+                bind_group,
+                spawn_pipeline,
+                update_pipeline,
+                max,
+                rate:          sys_desc.rate,
+                material:      sys_desc.material,
+                transform:     Transform {
+                    translation: sys_desc.pos,
+                    rotation:    sys_desc.rotation.normalized(),
+                    scale:       sys_desc.scale,
+                },
+                name:          sys_desc.name.to_string(),
+                life:          sys_desc.life,
+                duration:      sys_desc.life,
+                looping:       sys_desc.looping,
+                bounds:        sys_desc.bounds,
+                forces:        Vec::new(),
+                attractors:    Vec::new(),
+                attractor_cap: 1,
+                rand:          Randf32::new(),
+                slot_life:     vec![0.0; max],
+                free:          VecDeque::from((0..max).collect::<Vec<usize>>()),
+                spawns:        Vec::with_capacity(max),
+            }
+        )
+    }
+
+    /// Spawn new particles and run the simulation on the GPU, should be called every frame.
+    pub fn update(&mut self, delta: Duration, device: &wgpu::Device, queue: &wgpu::Queue) {
+        let delta = delta.as_millis() as f32 / 1000.0;
+
+        if self.life >= 0.0 {
+            self.spawn_particles();
+        }
+        self.life -= delta;
+        if self.life < 0.0 && self.looping && self.duration > 0.0 {
+            self.life += self.duration;
+        }
+
+        // Mirror particle lifetimes on the CPU so free slots are known
+        // without reading anything back from the GPU.
+        for (slot, life) in self.slot_life.iter_mut().enumerate() {
+            if *life > 0.0 {
+                *life -= delta;
+                if *life <= 0.0 {
+                    self.free.push_back(slot);
+                }
+            }
+        }
+
+        if self.attractors.len() > self.attractor_cap {
+            self.attractor_cap = self.attractors.len();
+            self.attractor_buf = create_attractor_buf(device, self.attractor_cap);
+            self.bind_group = create_bind_group(
+                device,
+                &self.bind_layout,
+                [
+                    &self.params_buf,
+                    &self.state_buf,
+                    &self.spawn_buf,
+                    &self.attractor_buf,
+                    &self.instance_buf,
+                ],
+            );
+        }
+        let attractors = self.attractors.iter()
+            .map(|att| [att.pos.x, att.pos.y, att.pos.z, att.mass])
+            .collect::<Vec<[f32; 4]>>();
+        queue.write_buffer(&self.attractor_buf, 0, bytemuck::cast_slice(&attractors));
+
+        let force = self.forces.iter().sum::<Vec3>();
+        let params = GpuParams {
+            force:           [force.x, force.y, force.z, 0.0],
+            delta,
+            spawn_count:     self.spawns.len() as u32,
+            particle_count:  self.max as u32,
+            attractor_count: self.attractors.len() as u32,
+            material:        self.material,
+            ..Default::default()
+        };
+        queue.write_buffer(&self.params_buf, 0, bytemuck::cast_slice(&[params]));
+        queue.write_buffer(&self.spawn_buf, 0, bytemuck::cast_slice(&self.spawns));
+
+        let mut encoder = device.create_command_encoder(
+            &wgpu::CommandEncoderDescriptor {
+                label: Some("Compute Encoder"),
+            }
+        );
+        {
+            let mut pass = encoder.begin_compute_pass(
+                &wgpu::ComputePassDescriptor {
+                    label: Some("Particle Compute Pass"),
+                }
+            );
+            pass.set_bind_group(0, &self.bind_group, &[]);
+            if !self.spawns.is_empty() {
+                pass.set_pipeline(&self.spawn_pipeline);
+                pass.dispatch_workgroups(workgroups(self.spawns.len()), 1, 1);
+            }
+            pass.set_pipeline(&self.update_pipeline);
+            pass.dispatch_workgroups(workgroups(self.max), 1, 1);
+        }
+        queue.submit(Some(encoder.finish()));
+        self.spawns.clear();
+    }
+
+    fn spawn_particles(&mut self) {
+        for _ in 0..self.rate {
+            if let Some(slot) = self.free.pop_front() {
+                let particle = Particle::new(&mut self.rand, &self.bounds)
+                    .transformed(&self.transform);
+                self.slot_life[slot] = particle.life;
+                self.spawns.push(GpuParticle::new(&particle, slot));
+            }
+            else {
+                return;
+            }
+        }
+    }
+
+    /// Read living particles back from the GPU. This stalls until the GPU
+    /// has finished all submitted work, so it is meant for tests and tools.
+    pub fn read_particles(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Vec<ParticleView> {
+        let size = self.max as u64 * GpuParticle::size();
+        let staging = device.create_buffer(
+            &wgpu::BufferDescriptor {
+                label: Some("GPU Particle Staging Buffer"),
+                size,
+                usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }
+        );
+        let mut encoder = device.create_command_encoder(
+            &wgpu::CommandEncoderDescriptor {
+                label: Some("Readback Encoder"),
+            }
+        );
+        encoder.copy_buffer_to_buffer(&self.state_buf, 0, &staging, 0, size);
+        queue.submit(Some(encoder.finish()));
+
+        let slice = staging.slice(..);
+        slice.map_async(wgpu::MapMode::Read, |_| {});
+        device.poll(wgpu::Maintain::Wait);
+
+        let particles = bytemuck::cast_slice::<u8, GpuParticle>(&slice.get_mapped_range())
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| p.position[3] > 0.0)
+            .map(|(i, p)| ParticleView::new(i, &p.particle()))
+            .collect();
+        staging.unmap();
+        particles
+    }
+
+    /// Return reference to instance buffer written by the compute pass.
+    pub fn particle_buf(&self) -> &wgpu::Buffer {
+        &self.instance_buf
+    }
+
+    /// Return maximum number of particles in particle system.
+    pub fn particle_count(&self) -> u32 {
+        self.max as u32
+    }
+
+    /// Return name of particle system.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Set position of particle system.
+    pub fn set_position(&mut self, position: [f32; 3]) {
+        self.transform.translation = position.into();
+    }
+
+    /// Set translation, rotation and scale of particle system.
+    pub fn set_transform(&mut self, transform: Transform) {
+        self.transform = transform;
+    }
+
+    /// Set number of particles spawned per frame.
+    pub fn set_rate(&mut self, rate: usize) {
+        self.rate = rate;
+    }
+
+    /// Set material index written to every instance, see
+    /// [`ParticleSystem::set_material`].
+    ///
+    /// [`ParticleSystem::set_material`]: crate::particle_system::ParticleSystem::set_material
+    pub fn set_material(&mut self, material: u32) {
+        self.material = material;
+    }
+
+    pub fn add_force(&mut self, force: [f32; 3]) {
+        self.forces.push(force.into());
+    }
+
+    pub fn add_attractor(&mut self, pos: [f32; 3], mass: f32) {
+        self.attractors.push(ParticleAttractor::new(pos, mass));
+    }
+}
+
+/// Particle state as stored on the GPU.
+#[repr(C)]
+#[derive(Copy, Clone, Default, bytemuck::Pod, bytemuck::Zeroable)]
+struct GpuParticle {
+    position: [f32; 4],
+    velocity: [f32; 4],
+    rotation: [f32; 4],
+    color:    [f32; 4],
+    extra:    [f32; 4],
+}
+impl GpuParticle {
+    fn new(p: &Particle, slot: usize) -> Self {
+        Self {
+            position: [p.position.x, p.position.y, p.position.z, p.life],
+            velocity: [p.velocity.x, p.velocity.y, p.velocity.z, p.age],
+            rotation: p.rotation.into(),
+            color:    p.color.into(),
+            extra:    [p.scale, p.mass, 0.0, f32::from_bits(slot as u32)],
+        }
+    }
+
+    fn particle(&self) -> Particle {
+        Particle {
+            position: Vec3::new(self.position[0], self.position[1], self.position[2]),
+            velocity: Vec3::new(self.velocity[0], self.velocity[1], self.velocity[2]),
+            rotation: Quaternion::from(self.rotation),
+            scale:    self.extra[0],
+            life:     self.position[3],
+            age:      self.velocity[3],
+            mass:     self.extra[1],
+            color:    Vec4::from(self.color),
+            seed:     0,
+        }
+    }
+
+    fn size() -> u64 {
+        mem::size_of::<Self>() as u64
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default, bytemuck::Pod, bytemuck::Zeroable)]
+struct GpuParams {
+    force:           [f32; 4],
+    delta:           f32,
+    spawn_count:     u32,
+    particle_count:  u32,
+    attractor_count: u32,
+    material:        u32,
+    _padding:        [u32; 3],
+}
+
+fn workgroups(count: usize) -> u32 {
+    (count as u32).div_ceil(WORKGROUP_SIZE)
+}
+
+fn layout_entry(binding: u32, ty: wgpu::BufferBindingType) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::COMPUTE,
+        ty: wgpu::BindingType::Buffer {
+            ty,
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
+
+fn create_attractor_buf(device: &wgpu::Device, count: usize) -> wgpu::Buffer {
+    device.create_buffer(
+        &wgpu::BufferDescriptor {
+            label: Some("GPU Attractor Buffer"),
+            size: count as u64 * 16,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        }
+    )
+}
+
+fn create_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    bufs: [&wgpu::Buffer; 5],
+) -> wgpu::BindGroup {
+    let entries = bufs.iter()
+        .enumerate()
+        .map(|(i, buf)| wgpu::BindGroupEntry {
+            binding: i as u32,
+            resource: buf.as_entire_binding(),
+        })
+        .collect::<Vec<wgpu::BindGroupEntry>>();
+
+    device.create_bind_group(
+        &wgpu::BindGroupDescriptor {
+            label: Some("GPU Particle Bind Group"),
+            layout,
+            entries: &entries,
+        }
+    )
+}
+
+
+/// Device for tests that can also run without one.
+#[cfg(test)]
+pub(crate) fn test_device() -> Option<(wgpu::Device, wgpu::Queue)> {
+    let instance = wgpu::Instance::new(wgpu::Backends::all());
+    let adapter = pollster::block_on(instance.request_adapter(
+        &wgpu::RequestAdapterOptions {
+            force_fallback_adapter: true,
+            ..Default::default()
+        }
+    ))?;
+    pollster::block_on(adapter.request_device(&wgpu::DeviceDescriptor::default(), None)).ok()
+}
+
+/// Device for tests marked `#[ignore = "requires a GPU adapter"]`, run
+/// with `cargo test -- --ignored`.
+#[cfg(test)]
+pub(crate) fn gpu_device() -> (wgpu::Device, wgpu::Queue) {
+    test_device().expect("no GPU adapter found")
+}
+
+/// Read the contents of a buffer created with COPY_SRC usage.
+#[cfg(test)]
+pub(crate) fn read_buffer(device: &wgpu::Device, queue: &wgpu::Queue, buf: &wgpu::Buffer) -> Vec<u8> {
+    let staging = device.create_buffer(
+        &wgpu::BufferDescriptor {
+            label: Some("Test Staging Buffer"),
+            size: buf.size(),
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        }
+    );
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+    encoder.copy_buffer_to_buffer(buf, 0, &staging, 0, buf.size());
+    queue.submit(Some(encoder.finish()));
+
+    let slice = staging.slice(..);
+    slice.map_async(wgpu::MapMode::Read, |_| {});
+    device.poll(wgpu::Maintain::Wait);
+    let data = slice.get_mapped_range().to_vec();
+    staging.unmap();
+    data
+}
+
+#[test]
+#[ignore = "requires a GPU adapter"]
+fn gpu_matches_cpu() {
+    use crate::particle_system::ParticleSystem;
+
+    let (device, queue) = gpu_device();
+    let desc = ParticleSystemDescriptor {
+        max: 256,
+        rate: 4,
+        material: 7,
+        ..Default::default()
+    };
+
+    let mut cpu = ParticleSystem::new(&device, &desc).unwrap();
+    let mut gpu = GpuParticleSystem::new(&device, &desc).unwrap();
+    let invalid = ParticleSystemDescriptor { max: 2, rate: 3, ..Default::default() };
+    assert!(GpuParticleSystem::new(&device, &invalid).is_err());
+    cpu.add_force([0.0, -9.8, 0.0]);
+    gpu.add_force([0.0, -9.8, 0.0]);
+    cpu.add_attractor([1.0, 2.0, 0.0], 1e9);
+    gpu.add_attractor([1.0, 2.0, 0.0], 1e9);
+
+    let delta = Duration::from_millis(16);
+    for _ in 0..30 {
+        cpu.update(delta, &queue, [0.0, 0.0, 5.0]);
+        gpu.update(delta, &device, &queue);
+    }
+
+    // Nothing has died yet, so both paths store particles in spawn order.
+    let cpu_particles = cpu.particles().collect::<Vec<ParticleView>>();
+    let gpu_particles = gpu.read_particles(&device, &queue);
+    assert!(cpu_particles.len() == 120);
+    assert!(cpu_particles.len() == gpu_particles.len());
+
+    for (c, g) in cpu_particles.iter().zip(gpu_particles.iter()) {
+        for i in 0..3 {
+            assert!((c.position[i] - g.position[i]).abs() < 0.0001);
+            assert!((c.velocity[i] - g.velocity[i]).abs() < 0.0001);
+        }
+        assert!((c.life - g.life).abs() < 0.0001);
+    }
+
+    let instances = read_buffer(&device, &queue, gpu.particle_buf());
+    let instances = bytemuck::cast_slice::<u8, [u32; 10]>(&instances);
+    for (inst, g) in instances.iter().zip(gpu_particles.iter()) {
+        assert!(f32::from_bits(inst[0]) == g.position[0]);
+        assert!(inst[9] == 7);
+    }
+}
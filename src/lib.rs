@@ -1,21 +1,44 @@
 mod particle;
+#[cfg(feature = "wgpu")]
 mod texture;
 mod random;
 mod vector;
 mod matrix;
 mod quaternion;
+#[cfg(feature = "wgpu")]
 mod obj;
+pub mod transform;
+#[cfg(feature = "wgpu")]
 pub mod particle_system_renderer;
 pub mod error;
 pub mod particle_system;
+#[cfg(feature = "wgpu")]
+pub mod gpu_particle_system;
+pub mod frustum;
+pub mod snapshot;
+pub mod curve;
+pub mod expr;
+pub mod reload;
+#[cfg(feature = "serde")]
+pub mod effect;
 
-use crate::error::BrumousResult;
-use crate::particle_system::ParticleSystem;
-use crate::particle_system::ParticleSystemSet;
-use crate::particle_system_renderer::ParticleSystemRenderer;
+use crate::error::{BrumousError, BrumousResult};
+#[cfg(feature = "wgpu")]
+use crate::particle_system::{ParticleSystem, ParticleSystemSet};
+#[cfg(feature = "wgpu")]
+use crate::gpu_particle_system::GpuParticleSystem;
+#[cfg(feature = "wgpu")]
+use crate::particle_system_renderer::{ParticleSystemRenderer, FALLBACK_INSTANCES};
+#[cfg(feature = "wgpu")]
+use crate::particle::{ParticleInstance, ParticleMesh};
 use crate::vector::Vec3;
+use crate::quaternion::Quaternion;
+use crate::frustum::OffscreenMode;
+#[cfg(all(feature = "serde", feature = "wgpu"))]
+use crate::effect::EffectDescriptor;
 
 /// Creates a new particle system.
+#[cfg(feature = "wgpu")]
 pub trait CreateParticleSystem {
     fn create_particle_system(
         &self, 
@@ -27,13 +50,35 @@ pub trait CreateParticleSystem {
         descs: &[&ParticleSystemDescriptor],
     ) -> BrumousResult<ParticleSystemSet>;
 
+    fn create_gpu_particle_system(
+        &self,
+        desc: &ParticleSystemDescriptor,
+    ) -> BrumousResult<GpuParticleSystem>;
+
     fn create_particle_system_renderer(
         &self,
         queue: &wgpu::Queue, 
         config: &wgpu::SurfaceConfiguration,
         desc: &ParticleSystemRendererDescriptor, 
     ) -> BrumousResult<ParticleSystemRenderer>;
+
+    /// Create a set containing every system in an effect.
+    #[cfg(feature = "serde")]
+    fn create_effect(
+        &self,
+        effect: &EffectDescriptor,
+    ) -> BrumousResult<ParticleSystemSet>;
+
+    /// Create the renderer described by an effect.
+    #[cfg(feature = "serde")]
+    fn create_effect_renderer(
+        &self,
+        queue: &wgpu::Queue,
+        config: &wgpu::SurfaceConfiguration,
+        effect: &EffectDescriptor,
+    ) -> BrumousResult<ParticleSystemRenderer>;
 }
+#[cfg(feature = "wgpu")]
 impl CreateParticleSystem for wgpu::Device {
     fn create_particle_system(
         &self, 
@@ -53,6 +98,13 @@ impl CreateParticleSystem for wgpu::Device {
         Ok(ParticleSystemSet::new(systems))
     }
 
+    fn create_gpu_particle_system(
+        &self,
+        desc: &ParticleSystemDescriptor,
+    ) -> BrumousResult<GpuParticleSystem> {
+        GpuParticleSystem::new(self, desc)
+    }
+
     fn create_particle_system_renderer(
         &self,
         queue: &wgpu::Queue, 
@@ -61,10 +113,31 @@ impl CreateParticleSystem for wgpu::Device {
     ) -> BrumousResult<ParticleSystemRenderer> {
         ParticleSystemRenderer::new(self, queue, config, desc)
     }
+
+    #[cfg(feature = "serde")]
+    fn create_effect(
+        &self,
+        effect: &EffectDescriptor,
+    ) -> BrumousResult<ParticleSystemSet> {
+        effect.create_systems(self)
+    }
+
+    #[cfg(feature = "serde")]
+    fn create_effect_renderer(
+        &self,
+        queue: &wgpu::Queue,
+        config: &wgpu::SurfaceConfiguration,
+        effect: &EffectDescriptor,
+    ) -> BrumousResult<ParticleSystemRenderer> {
+        effect.create_renderer(self, queue, config)
+    }
 }
 
 /// Draw particles in particle system
+#[cfg(feature = "wgpu")]
 pub trait DrawParticleSystem<'a, 'b> where 'a: 'b {
+    /// Draw a system. If its uploaded attributes don't match those read by
+    /// the renderer, the renderer's zeroed fallback attributes are bound.
     fn draw_particle_system(
         &'b mut self, 
         sys: &'a ParticleSystem, 
@@ -76,28 +149,76 @@ pub trait DrawParticleSystem<'a, 'b> where 'a: 'b {
         set: &'a ParticleSystemSet, 
         rend: &'a ParticleSystemRenderer
     );
+
+    /// Draw with instance count read from the system's indirect args
+    /// buffer. The vertex or index count of the renderer's mesh is written
+    /// to the args through `queue`, so a system drawn with several
+    /// renderers in one submission uses the mesh of the last one.
+    fn draw_particle_system_indirect(
+        &'b mut self, 
+        queue: &wgpu::Queue,
+        sys: &'a ParticleSystem, 
+        rend: &'a ParticleSystemRenderer
+    );
+
+    fn draw_particle_system_set_indirect(
+        &'b mut self, 
+        queue: &wgpu::Queue,
+        set: &'a ParticleSystemSet, 
+        rend: &'a ParticleSystemRenderer
+    );
+
+    /// Draw a merged set with a single call, see [`ParticleSystemSet::set_merged`].
+    /// The renderer's main mesh is used for every system, and custom
+    /// attributes are bound to zeroed fallback data.
+    fn draw_particle_system_set_merged(
+        &'b mut self, 
+        set: &'a ParticleSystemSet, 
+        rend: &'a ParticleSystemRenderer
+    );
+
+    fn draw_gpu_particle_system(
+        &'b mut self, 
+        sys: &'a GpuParticleSystem, 
+        rend: &'a ParticleSystemRenderer
+    );
 }
+#[cfg(feature = "wgpu")]
 impl<'a, 'b> DrawParticleSystem<'a, 'b> for wgpu::RenderPass<'a> where 'a: 'b {
     fn draw_particle_system(
         &'b mut self, 
         sys: &'a ParticleSystem, 
         rend: &'a ParticleSystemRenderer
     ) {
-        self.set_pipeline(&rend.pipeline);
+        let Some(particle_buf) = sys.particle_buf() else {
+            return;
+        };
+        if !sys.is_visible() {
+            return;
+        }
+        let (pipeline, mesh) = rend.lod_mesh(sys.lod_mesh());
+        self.set_pipeline(pipeline);
 
         for (i, group) in rend.bind_groups.iter().enumerate() {
             self.set_bind_group(i as u32, group, &[]);
         }
 
-        self.set_vertex_buffer(0, rend.mesh.vertex_buf.slice(..));
-        self.set_vertex_buffer(1, sys.particle_buf().slice(..));
+        self.set_vertex_buffer(0, mesh.vertex_buf.slice(..));
+        if !attributes_match(sys, rend) {
+            draw_without_attributes(self, rend, mesh, particle_buf, sys.instance_count());
+            return;
+        }
+        self.set_vertex_buffer(1, particle_buf.slice(..));
+        if let Some(attr_buf) = sys.attribute_buf().filter(|_| rend.attribute_count > 0) {
+            self.set_vertex_buffer(2, attr_buf.slice(..));
+        }
 
-        if let Some(index_buf) = &rend.mesh.index_buf {
+        if let Some(index_buf) = &mesh.index_buf {
             self.set_index_buffer(index_buf.slice(..), wgpu::IndexFormat::Uint16);
-            self.draw_indexed(0..rend.mesh.index_count, 0, 0..sys.particle_count());
+            self.draw_indexed(0..mesh.index_count, 0, 0..sys.instance_count());
         }
         else {
-            self.draw(0..rend.mesh.vertex_count, 0..sys.particle_count());
+            self.draw(0..mesh.vertex_count, 0..sys.instance_count());
         }
     }
 
@@ -107,50 +228,422 @@ impl<'a, 'b> DrawParticleSystem<'a, 'b> for wgpu::RenderPass<'a> where 'a: 'b {
         rend: &'a ParticleSystemRenderer
     ) {
         for sys in set.systems().iter() {
-            self.set_pipeline(&rend.pipeline);
+            self.draw_particle_system(sys, rend);
+        }
+    }
 
-            for (i, group) in rend.bind_groups.iter().enumerate() {
-                self.set_bind_group(i as u32, group, &[]);
-            }
+    fn draw_particle_system_indirect(
+        &'b mut self, 
+        queue: &wgpu::Queue,
+        sys: &'a ParticleSystem, 
+        rend: &'a ParticleSystemRenderer
+    ) {
+        let (Some(particle_buf), Some(indirect_buf)) = (sys.particle_buf(), sys.indirect_buf()) else {
+            return;
+        };
+        if !sys.is_visible() {
+            return;
+        }
+        if !attributes_match(sys, rend) {
+            self.draw_particle_system(sys, rend);
+            return;
+        }
+        let (pipeline, mesh) = rend.lod_mesh(sys.lod_mesh());
+        // Queued writes land before the next submission, so the args are
+        // in place when this pass runs.
+        queue.write_buffer(indirect_buf, 0, bytemuck::cast_slice(&[mesh.draw_count()]));
+        self.set_pipeline(pipeline);
 
-            self.set_vertex_buffer(0, rend.mesh.vertex_buf.slice(..));
-            self.set_vertex_buffer(1, sys.particle_buf().slice(..));
+        for (i, group) in rend.bind_groups.iter().enumerate() {
+            self.set_bind_group(i as u32, group, &[]);
+        }
 
-            if let Some(index_buf) = &rend.mesh.index_buf {
-                self.set_index_buffer(index_buf.slice(..), wgpu::IndexFormat::Uint16);
-                self.draw_indexed(0..rend.mesh.index_count, 0, 0..sys.particle_count());
-            }
-            else {
-                self.draw(0..rend.mesh.vertex_count, 0..sys.particle_count());
-            }
+        self.set_vertex_buffer(0, mesh.vertex_buf.slice(..));
+        self.set_vertex_buffer(1, particle_buf.slice(..));
+        if let Some(attr_buf) = sys.attribute_buf().filter(|_| rend.attribute_count > 0) {
+            self.set_vertex_buffer(2, attr_buf.slice(..));
+        }
+
+        if let Some(index_buf) = &mesh.index_buf {
+            self.set_index_buffer(index_buf.slice(..), wgpu::IndexFormat::Uint16);
+            self.draw_indexed_indirect(indirect_buf, 0);
+        }
+        else {
+            self.draw_indirect(indirect_buf, 0);
+        }
+    }
+
+    fn draw_particle_system_set_indirect(
+        &'b mut self, 
+        queue: &wgpu::Queue,
+        set: &'a ParticleSystemSet, 
+        rend: &'a ParticleSystemRenderer
+    ) {
+        for sys in set.systems().iter() {
+            self.draw_particle_system_indirect(queue, sys, rend);
+        }
+    }
+
+    fn draw_particle_system_set_merged(
+        &'b mut self, 
+        set: &'a ParticleSystemSet, 
+        rend: &'a ParticleSystemRenderer
+    ) {
+        let Some(merged_buf) = set.merged_buf() else {
+            return;
+        };
+        self.set_pipeline(&rend.pipeline);
+
+        for (i, group) in rend.bind_groups.iter().enumerate() {
+            self.set_bind_group(i as u32, group, &[]);
+        }
+
+        self.set_vertex_buffer(0, rend.mesh.vertex_buf.slice(..));
+        draw_without_attributes(self, rend, &rend.mesh, merged_buf, set.merged_count());
+    }
+
+    fn draw_gpu_particle_system(
+        &'b mut self, 
+        sys: &'a GpuParticleSystem, 
+        rend: &'a ParticleSystemRenderer
+    ) {
+        self.set_pipeline(&rend.pipeline);
+
+        for (i, group) in rend.bind_groups.iter().enumerate() {
+            self.set_bind_group(i as u32, group, &[]);
+        }
+
+        self.set_vertex_buffer(0, rend.mesh.vertex_buf.slice(..));
+        draw_without_attributes(self, rend, &rend.mesh, sys.particle_buf(), sys.particle_count());
+    }
+}
+
+/// Return whether a system's uploaded attributes match those read by the
+/// renderer's shader. Systems that don't match are drawn with the
+/// renderer's zeroed fallback attributes.
+#[cfg(feature = "wgpu")]
+fn attributes_match(sys: &ParticleSystem, rend: &ParticleSystemRenderer) -> bool {
+    rend.attribute_count == 0
+        || sys.uploaded_attribute_count() == rend.attribute_count as usize
+        && sys.attribute_buf().is_some()
+}
+
+/// Draw instances that have no custom attributes with a mesh of the
+/// renderer. If the renderer reads attributes, its zeroed fallback buffer
+/// is bound instead, and instances are drawn in batches the size of it.
+#[cfg(feature = "wgpu")]
+fn draw_without_attributes<'a>(
+    pass: &mut wgpu::RenderPass<'a>,
+    rend: &'a ParticleSystemRenderer,
+    mesh: &'a ParticleMesh,
+    instances: &'a wgpu::Buffer,
+    count: u32,
+) {
+    let batch_size = match &rend.attribute_fallback {
+        Some(fallback) => {
+            pass.set_vertex_buffer(2, fallback.slice(..));
+            FALLBACK_INSTANCES
         }
+        None => count.max(1),
+    };
+    if let Some(index_buf) = &mesh.index_buf {
+        pass.set_index_buffer(index_buf.slice(..), wgpu::IndexFormat::Uint16);
+    }
+    let mut first = 0;
+    while first < count {
+        let batch = (count - first).min(batch_size);
+        pass.set_vertex_buffer(1, instances.slice(first as u64 * ParticleInstance::size()..));
+        if mesh.index_buf.is_some() {
+            pass.draw_indexed(0..mesh.index_count, 0, 0..batch);
+        }
+        else {
+            pass.draw(0..mesh.vertex_count, 0..batch);
+        }
+        first += batch;
     }
 }
 
 /// Describe characteristics of a particle system.
+///
+/// `life` is how long the system emits particles for, in seconds. If
+/// `looping` is set, emission restarts each time `life` elapses.
+///
+/// `inherit_velocity` is the fraction of the emitter's velocity added to
+/// new particles, and `rate_over_distance` is the number of particles
+/// spawned per unit the emitter moves, on top of `rate`.
+///
+/// `rotation` and `scale` orient the spawn area and initial velocities.
 pub struct ParticleSystemDescriptor<'a> {
-    pub max:    usize,
-    pub rate:   usize,
-    pub pos:    Vec3,
-    pub name:   &'a str,
-    pub life:   f32,
-    pub bounds: ParticleSystemBounds,
+    pub max:                usize,
+    pub rate:               usize,
+    pub rate_over_distance: f32,
+    pub pos:                Vec3,
+    pub rotation:           Quaternion,
+    pub scale:              Vec3,
+    pub space:              SimulationSpace,
+    pub inherit_velocity:   f32,
+    pub name:               &'a str,
+    pub life:               f32,
+    pub looping:            bool,
+    pub sort:               SortMode,
+    pub offscreen:          OffscreenMode,
+    pub lod:                &'a [LodBand],
+    pub material:           u32,
+    pub bounds:             ParticleSystemBounds,
 }
 impl<'a> Default for ParticleSystemDescriptor<'a> {
     fn default() -> Self {
         Self {
-            max:    500,
-            rate:   1,
-            pos:    Vec3::zero(),
-            name:   "Particle System",
-            life:   1000.0,
-            bounds: ParticleSystemBounds::default(),
+            max:                500,
+            rate:               1,
+            rate_over_distance: 0.0,
+            pos:                Vec3::zero(),
+            rotation:           Quaternion::identity(),
+            scale:              Vec3::new(1.0, 1.0, 1.0),
+            space:              SimulationSpace::default(),
+            inherit_velocity:   0.0,
+            name:               "Particle System",
+            life:               1000.0,
+            looping:            false,
+            sort:               SortMode::default(),
+            offscreen:          OffscreenMode::default(),
+            lod:                &[],
+            material:           0,
+            bounds:             ParticleSystemBounds::default(),
+        }
+    }
+}
+impl<'a> ParticleSystemDescriptor<'a> {
+    /// Return builder starting from the default descriptor.
+    pub fn builder() -> ParticleSystemDescriptorBuilder<'a> {
+        ParticleSystemDescriptorBuilder::default()
+    }
+
+    /// Check for settings a system can't be simulated with, such as zero
+    /// max particles, negative lifetimes, NaN bounds, zero mass or invalid
+    /// LOD bands.
+    pub fn validate(&self) -> BrumousResult<()> {
+        let name = || self.name.to_string();
+        if self.max == 0 {
+            return Err(BrumousError::ZeroMaxParticles(name()));
+        }
+        if self.rate > self.max {
+            return Err(BrumousError::RateExceedsMax(name(), self.rate, self.max));
+        }
+        if self.life < 0.0 || self.life.is_nan() {
+            return Err(BrumousError::NegativeLife(name(), self.life));
+        }
+        let bounds = &self.bounds;
+        let fields: [(&str, &[(f32, f32)]); 7] = [
+            ("area", &bounds.area),
+            ("velocity", &bounds.velocity),
+            ("rotation", &bounds.rotation),
+            ("color", &bounds.color),
+            ("life", &[bounds.life]),
+            ("mass", &[bounds.mass]),
+            ("scale", &[bounds.scale]),
+        ];
+        for (field, values) in fields {
+            if values.iter().any(|(mean, var)| mean.is_nan() || var.is_nan()) {
+                return Err(BrumousError::NaNBounds(name(), field.to_string()));
+            }
+        }
+        let (mean, var) = bounds.life;
+        if mean - var.abs() < 0.0 {
+            return Err(BrumousError::NegativeParticleLife(name(), mean, var));
+        }
+        let (mean, var) = bounds.mass;
+        if mean - var.abs() <= 0.0 {
+            return Err(BrumousError::ZeroMass(name(), mean, var));
+        }
+        if let Some(idx) = self.lod.iter().position(|band| band.distance.is_nan()) {
+            return Err(BrumousError::NaNLodDistance(name(), idx));
+        }
+        for (idx, band) in self.lod.iter().enumerate() {
+            let field = if band.rate < 0.0 || band.rate.is_nan() {
+                "rate"
+            }
+            else if band.max < 0.0 || band.max.is_nan() {
+                "max"
+            }
+            else if band.interval == 0 {
+                "interval"
+            }
+            else {
+                continue;
+            };
+            return Err(BrumousError::InvalidLodBand(name(), idx, field.to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// Builds a [`ParticleSystemDescriptor`], validating it in
+/// [`ParticleSystemDescriptorBuilder::build`].
+#[derive(Default)]
+pub struct ParticleSystemDescriptorBuilder<'a> {
+    desc: ParticleSystemDescriptor<'a>,
+}
+impl<'a> ParticleSystemDescriptorBuilder<'a> {
+    pub fn max(mut self, max: usize) -> Self {
+        self.desc.max = max;
+        self
+    }
+
+    pub fn rate(mut self, rate: usize) -> Self {
+        self.desc.rate = rate;
+        self
+    }
+
+    pub fn rate_over_distance(mut self, rate: f32) -> Self {
+        self.desc.rate_over_distance = rate;
+        self
+    }
+
+    pub fn position(mut self, pos: [f32; 3]) -> Self {
+        self.desc.pos = pos.into();
+        self
+    }
+
+    /// Set rotation quaternion in [s, x, y, z] order.
+    pub fn rotation(mut self, rotation: [f32; 4]) -> Self {
+        self.desc.rotation = Quaternion::from(rotation);
+        self
+    }
+
+    pub fn scale(mut self, scale: [f32; 3]) -> Self {
+        self.desc.scale = scale.into();
+        self
+    }
+
+    pub fn space(mut self, space: SimulationSpace) -> Self {
+        self.desc.space = space;
+        self
+    }
+
+    pub fn inherit_velocity(mut self, fraction: f32) -> Self {
+        self.desc.inherit_velocity = fraction;
+        self
+    }
+
+    pub fn name(mut self, name: &'a str) -> Self {
+        self.desc.name = name;
+        self
+    }
+
+    /// Set how long the system emits particles for, in seconds.
+    pub fn life(mut self, life: f32) -> Self {
+        self.desc.life = life;
+        self
+    }
+
+    pub fn looping(mut self, looping: bool) -> Self {
+        self.desc.looping = looping;
+        self
+    }
+
+    pub fn sort(mut self, sort: SortMode) -> Self {
+        self.desc.sort = sort;
+        self
+    }
+
+    pub fn offscreen(mut self, offscreen: OffscreenMode) -> Self {
+        self.desc.offscreen = offscreen;
+        self
+    }
+
+    pub fn lod(mut self, lod: &'a [LodBand]) -> Self {
+        self.desc.lod = lod;
+        self
+    }
+
+    pub fn material(mut self, material: u32) -> Self {
+        self.desc.material = material;
+        self
+    }
+
+    pub fn bounds(mut self, bounds: ParticleSystemBounds) -> Self {
+        self.desc.bounds = bounds;
+        self
+    }
+
+    /// Set mean and variance of particle lifetimes.
+    pub fn particle_life(mut self, life: (f32, f32)) -> Self {
+        self.desc.bounds.life = life;
+        self
+    }
+
+    /// Set mean and variance of particle mass.
+    pub fn mass(mut self, mass: (f32, f32)) -> Self {
+        self.desc.bounds.mass = mass;
+        self
+    }
+
+    /// Return the descriptor, or an error if it is invalid, see
+    /// [`ParticleSystemDescriptor::validate`].
+    pub fn build(self) -> BrumousResult<ParticleSystemDescriptor<'a>> {
+        self.desc.validate()?;
+        Ok(self.desc)
+    }
+}
+
+/// Coordinate space particles are simulated in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SimulationSpace {
+    /// Particles are left behind as the emitter moves.
+    #[default]
+    World,
+    /// Particles move with the emitter. Forces, attractors and colliders
+    /// are interpreted in the emitter's local space.
+    Local,
+}
+
+/// Order particles are drawn in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SortMode {
+    /// Draw in storage order, for opaque particles.
+    None,
+    /// Draw farthest from the camera first, for alpha blending.
+    #[default]
+    BackToFront,
+    /// Draw closest to the camera first.
+    FrontToBack,
+    /// Draw oldest first, so newer particles are drawn on top.
+    ByAge,
+}
+
+/// Settings used while a system is at least `distance` from the view position.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LodBand {
+    pub distance: f32,
+    /// Multiplier of emission rate.
+    pub rate:     f32,
+    /// Fraction of max particles that may be alive.
+    pub max:      f32,
+    /// Simulate once every `interval` frames. Particles are only emitted
+    /// on simulated frames.
+    pub interval: u32,
+    /// Index into the renderer's LOD meshes, or None for its main mesh.
+    pub mesh:     Option<usize>,
+}
+impl Default for LodBand {
+    fn default() -> Self {
+        Self {
+            distance: 0.0,
+            rate:     1.0,
+            max:      1.0,
+            interval: 1,
+            mesh:     None,
         }
     }
 }
 
 /// Describes the mean and variance of a particle's traits.
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ParticleSystemBounds {
     pub area:     [(f32, f32); 3],
     pub velocity: [(f32, f32); 3],
@@ -175,24 +668,39 @@ impl Default for ParticleSystemBounds {
 }
 
 
+/// Number of lights a renderer holds unless its descriptor sets another.
+pub const DEFAULT_MAX_LIGHTS: usize = 4;
+
+#[cfg(feature = "wgpu")]
 pub struct ParticleSystemRendererDescriptor<'a> {
     pub texture: Option<&'a str>,
     pub mesh_type: ParticleMeshType<'a>,
     pub max_lights: usize,
     pub depth_texture: Option<DepthTextureDescriptor>,
+    /// Path to a custom WGSL shader, replacing the built-in shader.
+    pub shader: Option<&'a str>,
+    /// Number of vec4 custom attributes read by the shader, starting at location 13.
+    pub attributes: u32,
+    /// Meshes selected by [`LodBand::mesh`] for distant systems.
+    pub lod_meshes: &'a [ParticleMeshType<'a>],
 }
+#[cfg(feature = "wgpu")]
 impl<'a> Default for ParticleSystemRendererDescriptor<'a> {
     fn default() -> Self {
         Self {
             texture: None,
             mesh_type: ParticleMeshType::default(),
-            max_lights: 4,
+            max_lights: DEFAULT_MAX_LIGHTS,
             depth_texture: None,
+            shader: None,
+            attributes: 0,
+            lod_meshes: &[],
         }
     }
 }
 
 /// Defines model of each particle.
+#[cfg(feature = "wgpu")]
 #[derive(Default)]
 pub enum ParticleMeshType<'a> {
     #[default]
@@ -201,6 +709,99 @@ pub enum ParticleMeshType<'a> {
     Custom(&'a str),
 }
 
+#[cfg(feature = "wgpu")]
 pub struct DepthTextureDescriptor {
     pub texture_format: wgpu::TextureFormat,
-}
\ No newline at end of file
+}
+
+#[test]
+fn descriptor_validation() {
+    let desc = ParticleSystemDescriptor::builder()
+        .name("sparks")
+        .max(10)
+        .rate(5)
+        .build()
+        .unwrap();
+    assert!(desc.max == 10 && desc.name == "sparks");
+
+    let err = |builder: ParticleSystemDescriptorBuilder| builder.build().err().unwrap();
+    assert!(matches!(err(ParticleSystemDescriptor::builder().max(0)), BrumousError::ZeroMaxParticles(_)));
+    assert!(matches!(err(ParticleSystemDescriptor::builder().max(2).rate(3)), BrumousError::RateExceedsMax(_, 3, 2)));
+    assert!(matches!(err(ParticleSystemDescriptor::builder().life(-1.0)), BrumousError::NegativeLife(..)));
+    assert!(matches!(err(ParticleSystemDescriptor::builder().particle_life((1.0, 2.0))), BrumousError::NegativeParticleLife(..)));
+    assert!(matches!(err(ParticleSystemDescriptor::builder().mass((0.0, 0.0))), BrumousError::ZeroMass(..)));
+
+    let mut bounds = ParticleSystemBounds::default();
+    bounds.velocity[1].0 = f32::NAN;
+    let nan = err(ParticleSystemDescriptor::builder().bounds(bounds));
+    assert!(matches!(nan, BrumousError::NaNBounds(_, ref field) if field == "velocity"));
+
+    let lod = [LodBand::default(), LodBand { distance: f32::NAN, ..Default::default() }];
+    assert!(matches!(err(ParticleSystemDescriptor::builder().lod(&lod)), BrumousError::NaNLodDistance(_, 1)));
+    let bands = [
+        LodBand { rate: -1.0, ..Default::default() },
+        LodBand { max: f32::NAN, ..Default::default() },
+        LodBand { interval: 0, ..Default::default() },
+    ];
+    for (band, field) in bands.iter().zip(["rate", "max", "interval"]) {
+        let lod = [LodBand::default(), *band];
+        let invalid = err(ParticleSystemDescriptor::builder().lod(&lod));
+        assert!(matches!(invalid, BrumousError::InvalidLodBand(_, 1, ref f) if f == field));
+    }
+}
+
+#[cfg(feature = "wgpu")]
+#[test]
+#[ignore = "requires a GPU adapter"]
+fn indirect_args() {
+    use crate::gpu_particle_system::{gpu_device, read_buffer};
+
+    let (device, queue) = gpu_device();
+    let config = wgpu::SurfaceConfiguration {
+        usage:        wgpu::TextureUsages::RENDER_ATTACHMENT,
+        format:       wgpu::TextureFormat::Rgba8UnormSrgb,
+        width:        4,
+        height:       4,
+        present_mode: wgpu::PresentMode::Fifo,
+        alpha_mode:   wgpu::CompositeAlphaMode::Auto,
+    };
+    let rend = device.create_particle_system_renderer(&queue, &config, &ParticleSystemRendererDescriptor::default()).unwrap();
+    let attr_desc = ParticleSystemRendererDescriptor { attributes: 1, ..Default::default() };
+    let attr_rend = device.create_particle_system_renderer(&queue, &config, &attr_desc).unwrap();
+    let mut sys = device.create_particle_system(&ParticleSystemDescriptor { max: 3, rate: 3, ..Default::default() }).unwrap();
+    sys.update(std::time::Duration::from_millis(16), &queue, [0.0; 3]);
+
+    let target = device.create_texture(
+        &wgpu::TextureDescriptor {
+            label: Some("Test Target"),
+            size: wgpu::Extent3d { width: 4, height: 4, depth_or_array_layers: 1 },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: config.format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+        }
+    );
+    let view = target.create_view(&wgpu::TextureViewDescriptor::default());
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+    {
+        let mut pass = encoder.begin_render_pass(
+            &wgpu::RenderPassDescriptor {
+                label: None,
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &view,
+                    resolve_target: None,
+                    ops: wgpu::Operations { load: wgpu::LoadOp::Clear(wgpu::Color::BLACK), store: true },
+                })],
+                depth_stencil_attachment: None,
+            }
+        );
+        pass.draw_particle_system_indirect(&queue, &sys, &rend);
+        // Drawn with fallback attributes, as the system has none.
+        pass.draw_particle_system(&sys, &attr_rend);
+    }
+    queue.submit(Some(encoder.finish()));
+
+    let args: Vec<u32> = bytemuck::cast_slice(&read_buffer(&device, &queue, sys.indirect_buf().unwrap())).to_vec();
+    assert_eq!(args, [rend.mesh.draw_count(), 3, 0, 0, 0]);
+}
@@ -1,30 +1,30 @@
 use std::mem;
 
-use crate::{ParticleSystemBounds, ParticleMeshType};
+use crate::ParticleSystemBounds;
+#[cfg(feature = "wgpu")]
+use crate::ParticleMeshType;
+#[cfg(feature = "wgpu")]
 use crate::obj::read_obj_file;
+#[cfg(feature = "wgpu")]
 use crate::error::BrumousResult;
 use crate::vector::{Vec3, Vec4};
-use crate::matrix::{Mat3x3, Mat4x4};
 use crate::quaternion::Quaternion;
 use crate::random::Randf32;
-use crate::particle_system::{ParticleAnimation, ParticleAttractor};
+use crate::transform::Transform;
+use crate::particle_system::{ParticleAnimation, ParticleAttractor, ParticleCollider, ParticleChannel};
+use crate::expr::ExprVars;
+use crate::random::hash_f32;
 
 const G: f32 = 0.00000000006674;
 
 
-pub trait Instance {
-    fn instance(&self) -> Vec<ParticleInstance>;
-}
-impl Instance for Vec<Particle> {
-    fn instance(&self) -> Vec<ParticleInstance> {
-        self.iter().map(Particle::instance).collect::<Vec<ParticleInstance>>()
-    }
-}
-
+#[cfg(feature = "wgpu")]
 pub trait VertexLayout {
     fn layout() -> wgpu::VertexBufferLayout<'static>;
 }
 
+/// A single particle, used when spawning and inspecting particles.
+/// Living particles are stored in a [`ParticleStorage`].
 #[derive(Clone, Copy)]
 pub struct Particle {
     pub position: Vec3,
@@ -32,61 +32,81 @@ pub struct Particle {
     pub rotation: Quaternion,
     pub scale:    f32,
     pub life:     f32,
+    pub age:      f32,
     pub mass:     f32,
     pub color:    Vec4,
-    pub queued:   bool,
-    pub cam_dist: f32,
+    /// Hashed to the `random` variable of expression animations, so it
+    /// stays the same over the particle's life.
+    pub seed:     u32,
 }
 impl Particle {
-    pub fn new(rand: &mut Randf32, bounds: &ParticleSystemBounds, pos: &Vec3) -> Self {
+    pub fn new(rand: &mut Randf32, bounds: &ParticleSystemBounds) -> Self {
         Self {
-            position: rand.vec3_in(&bounds.area) + *pos,
+            position: rand.vec3_in(&bounds.area),
             velocity: rand.vec3_in(&bounds.velocity),
             rotation: rand.quat_in(&bounds.rotation),
             color:    rand.vec4_in(&bounds.color),
             scale:    rand.f32_in(&bounds.scale),
             life:     rand.f32_in(&bounds.life),
+            age:      0.0,
             mass:     rand.f32_in(&bounds.mass),
-            queued:   false,
-            cam_dist: 0.0,
+            seed:     rand.next_u32(),
         }
     }
-    pub fn update_pos(&mut self, delta: f32, atts: &[ParticleAttractor], forces: &[Vec3]) {
-        for att in atts.iter() {
-            let pa = att.pos - self.position;
-            let dist = pa.len();
-            let force = (G * att.mass * self.mass) / (dist * dist);
-            let acc = force / self.mass;
-            let grav_dir = pa.normalized();
-            self.velocity += grav_dir * (acc * delta);
+
+    /// Return particle moved from the emitter's local space by transform.
+    pub fn transformed(&self, transform: &Transform) -> Self {
+        // A zero quaternion is treated as no rotation when converted to a matrix.
+        let rotation = if self.rotation.is_zero() {
+            Quaternion::identity()
         }
-        
-        let acc = forces.iter().sum::<Vec3>() / self.mass;
-        self.velocity += acc * delta * 0.5;
+        else {
+            self.rotation
+        };
+        Self {
+            position: transform.transform_point(self.position),
+            velocity: transform.transform_vector(self.velocity),
+            rotation: transform.rotation * rotation,
+            ..*self
+        }
+    }
 
-        self.position += self.velocity * delta;
+    /// Return compact instance, the model and normal matrices are built
+    /// from it in the vertex shader.
+    #[cfg(feature = "wgpu")]
+    pub fn instance(&self, material: u32) -> ParticleInstance {
+        ParticleInstance {
+            position: self.position.into(),
+            scale:    self.scale,
+            rotation: self.rotation.into(),
+            color:    pack_color(self.color),
+            material,
+        }
     }
 
-    pub fn animate(&mut self, delta: f32, animation: &ParticleAnimation) {
-        match animation {
-            ParticleAnimation::Color(anim) => {
-                self.color = anim(self.color, delta);
-            }
-            ParticleAnimation::Scale(anim) => {
-                self.scale = anim(self.scale, delta);
-            }
+    /// Return variables of expressions evaluated for the particle.
+    pub fn expr_vars(&self, random: f32, time: f32) -> ExprVars {
+        ExprVars {
+            age:   self.age,
+            life:  self.life,
+            speed: self.velocity.len(),
+            random,
+            time,
         }
     }
 
-    pub fn instance(&self) -> ParticleInstance {
-        ParticleInstance {
-            model: (
-                Mat4x4::from_translation(self.position) *
-                Mat4x4::from(self.rotation) *
-                Mat4x4::from_scale(self.scale)
-            ).into(),
-            normal: Mat3x3::from(self.rotation).into(),
-            color: self.color.into(),
+    pub fn set_channel(&mut self, channel: ParticleChannel, value: f32) {
+        match channel {
+            ParticleChannel::Scale => self.scale = value,
+            ParticleChannel::Life => self.life = value,
+            ParticleChannel::Mass => self.mass = value,
+            ParticleChannel::Red => self.color.x = value,
+            ParticleChannel::Green => self.color.y = value,
+            ParticleChannel::Blue => self.color.z = value,
+            ParticleChannel::Alpha => self.color.w = value,
+            ParticleChannel::VelocityX => self.velocity.x = value,
+            ParticleChannel::VelocityY => self.velocity.y = value,
+            ParticleChannel::VelocityZ => self.velocity.z = value,
         }
     }
 }
@@ -98,32 +118,336 @@ impl Default for Particle {
             rotation: Quaternion::zero(),
             scale:    0.0,
             life:     0.0,
+            age:      0.0,
             mass:     0.0,
             color:    Vec4::zero(),
-            queued:   true,
-            cam_dist: 0.0,
+            seed:     0,
+        }
+    }
+}
+
+/// Move a particle out of any collider it has entered and reflect its velocity.
+/// Returns true if the particle collided with at least one collider.
+pub fn collide(position: &mut Vec3, velocity: &mut Vec3, colliders: &[ParticleCollider]) -> bool {
+    let mut collided = false;
+    for col in colliders.iter() {
+        let (normal, depth, restitution) = match *col {
+            ParticleCollider::Plane { point, normal, restitution } => {
+                (normal, -(*position - point).dot(normal), restitution)
+            }
+            ParticleCollider::Sphere { center, radius, restitution } => {
+                let offset = *position - center;
+                let dist = offset.len();
+                if dist == 0.0 {
+                    continue;
+                }
+                (offset / dist, radius - dist, restitution)
+            }
+        };
+        if depth > 0.0 && velocity.dot(normal) < 0.0 {
+            *position += normal * depth;
+            *velocity = velocity.reflect(normal) * restitution;
+            collided = true;
+        }
+    }
+    collided
+}
+
+/// Living particles stored as a struct of arrays. Dead particles are
+/// removed by swapping in the last particle, so the arrays stay packed.
+#[derive(Clone, Default)]
+pub struct ParticleStorage {
+    pub position: Vec<Vec3>,
+    pub velocity: Vec<Vec3>,
+    pub rotation: Vec<Quaternion>,
+    pub scale:    Vec<f32>,
+    pub life:     Vec<f32>,
+    pub age:      Vec<f32>,
+    pub mass:     Vec<f32>,
+    pub color:    Vec<Vec4>,
+    pub seed:     Vec<u32>,
+}
+impl ParticleStorage {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            position: Vec::with_capacity(capacity),
+            velocity: Vec::with_capacity(capacity),
+            rotation: Vec::with_capacity(capacity),
+            scale:    Vec::with_capacity(capacity),
+            life:     Vec::with_capacity(capacity),
+            age:      Vec::with_capacity(capacity),
+            mass:     Vec::with_capacity(capacity),
+            color:    Vec::with_capacity(capacity),
+            seed:     Vec::with_capacity(capacity),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.life.len()
+    }
+
+    pub fn push(&mut self, p: Particle) {
+        self.position.push(p.position);
+        self.velocity.push(p.velocity);
+        self.rotation.push(p.rotation);
+        self.scale.push(p.scale);
+        self.life.push(p.life);
+        self.age.push(p.age);
+        self.mass.push(p.mass);
+        self.color.push(p.color);
+        self.seed.push(p.seed);
+    }
+
+    pub fn get(&self, i: usize) -> Particle {
+        Particle {
+            position: self.position[i],
+            velocity: self.velocity[i],
+            rotation: self.rotation[i],
+            scale:    self.scale[i],
+            life:     self.life[i],
+            age:      self.age[i],
+            mass:     self.mass[i],
+            color:    self.color[i],
+            seed:     self.seed[i],
+        }
+    }
+
+    /// Remove particle at index, replacing it with the last particle.
+    pub fn swap_remove(&mut self, i: usize) {
+        self.position.swap_remove(i);
+        self.velocity.swap_remove(i);
+        self.rotation.swap_remove(i);
+        self.scale.swap_remove(i);
+        self.life.swap_remove(i);
+        self.age.swap_remove(i);
+        self.mass.swap_remove(i);
+        self.color.swap_remove(i);
+        self.seed.swap_remove(i);
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        self.position.truncate(len);
+        self.velocity.truncate(len);
+        self.rotation.truncate(len);
+        self.scale.truncate(len);
+        self.life.truncate(len);
+        self.age.truncate(len);
+        self.mass.truncate(len);
+        self.color.truncate(len);
+        self.seed.truncate(len);
+    }
+
+    /// Reduce life and increase age of every particle.
+    pub fn age(&mut self, delta: f32) {
+        for life in self.life.iter_mut() {
+            *life -= delta;
+        }
+        for age in self.age.iter_mut() {
+            *age += delta;
         }
     }
+
+    /// Split storage into chunks of at most size particles.
+    pub fn chunks_mut(&mut self, size: usize) -> Vec<ParticleChunk<'_>> {
+        let position = self.position.chunks_mut(size);
+        let mut velocity = self.velocity.chunks_mut(size);
+        let mut scale = self.scale.chunks_mut(size);
+        let mut color = self.color.chunks_mut(size);
+        let mut rotation = self.rotation.chunks(size);
+        let mut mass = self.mass.chunks_mut(size);
+        let mut life = self.life.chunks_mut(size);
+        let mut age = self.age.chunks(size);
+        let mut seed = self.seed.chunks(size);
+
+        let mut chunks = Vec::new();
+        for position in position {
+            chunks.push(
+                ParticleChunk {
+                    position,
+                    velocity: velocity.next().unwrap(),
+                    scale:    scale.next().unwrap(),
+                    color:    color.next().unwrap(),
+                    rotation: rotation.next().unwrap(),
+                    mass:     mass.next().unwrap(),
+                    life:     life.next().unwrap(),
+                    age:      age.next().unwrap(),
+                    seed:     seed.next().unwrap(),
+                }
+            );
+        }
+        chunks
+    }
 }
 
+/// Contiguous range of particles that can be updated independently.
+pub struct ParticleChunk<'a> {
+    pub position: &'a mut [Vec3],
+    pub velocity: &'a mut [Vec3],
+    pub scale:    &'a mut [f32],
+    pub color:    &'a mut [Vec4],
+    pub rotation: &'a [Quaternion],
+    pub mass:     &'a mut [f32],
+    pub life:     &'a mut [f32],
+    pub age:      &'a [f32],
+    pub seed:     &'a [u32],
+}
+impl<'a> ParticleChunk<'a> {
+    pub fn get(&self, i: usize) -> Particle {
+        Particle {
+            position: self.position[i],
+            velocity: self.velocity[i],
+            rotation: self.rotation[i],
+            scale:    self.scale[i],
+            life:     self.life[i],
+            age:      self.age[i],
+            mass:     self.mass[i],
+            color:    self.color[i],
+            seed:     self.seed[i],
+        }
+    }
+
+    /// Write the mutable fields of a particle back to the chunk.
+    fn set(&mut self, i: usize, p: &Particle) {
+        self.velocity[i] = p.velocity;
+        self.scale[i] = p.scale;
+        self.color[i] = p.color;
+        self.mass[i] = p.mass;
+        self.life[i] = p.life;
+    }
+
+    /// Accelerate particles towards attractors and by forces, then move them.
+    pub fn integrate(&mut self, delta: f32, atts: &[ParticleAttractor], forces: &[Vec3]) {
+        for att in atts.iter() {
+            // The particle's own mass cancels out of the acceleration.
+            let k = G * att.mass * delta;
+            for (pos, vel) in self.position.iter().zip(self.velocity.iter_mut()) {
+                let pa = att.pos - *pos;
+                let dist_sq = pa.len_sq();
+                *vel += pa.normalized() * (k / dist_sq);
+            }
+        }
+
+        let force = forces.iter().sum::<Vec3>();
+        for (vel, mass) in self.velocity.iter_mut().zip(self.mass.iter()) {
+            *vel += force * (delta * 0.5 / mass);
+        }
+
+        for (pos, vel) in self.position.iter_mut().zip(self.velocity.iter()) {
+            *pos += *vel * delta;
+        }
+    }
+
+    /// Resolve collisions, returning indices of particles that collided.
+    pub fn collide(&mut self, colliders: &[ParticleCollider]) -> Vec<usize> {
+        let mut collided = Vec::new();
+        if colliders.is_empty() {
+            return collided;
+        }
+        for (i, (pos, vel)) in self.position.iter_mut().zip(self.velocity.iter_mut()).enumerate() {
+            if collide(pos, vel, colliders) {
+                collided.push(i);
+            }
+        }
+        collided
+    }
+
+    pub fn animate(&mut self, delta: f32, animation: &ParticleAnimation, frame: &AnimationFrame) {
+        match animation {
+            ParticleAnimation::Color(anim) => {
+                for color in self.color.iter_mut() {
+                    *color = anim(*color, delta);
+                }
+            }
+            ParticleAnimation::Scale(anim) => {
+                for scale in self.scale.iter_mut() {
+                    *scale = anim(*scale, delta);
+                }
+            }
+            ParticleAnimation::ColorOverLife(curve) => {
+                for (i, color) in self.color.iter_mut().enumerate() {
+                    if let Some(c) = curve.sample(life_fraction(self.age[i], self.life[i])) {
+                        let tint = frame.curve_tint;
+                        *color = Vec4::new(c[0] * tint[0], c[1] * tint[1], c[2] * tint[2], c[3] * tint[3]);
+                    }
+                }
+            }
+            ParticleAnimation::ScaleOverLife(curve) => {
+                for (i, scale) in self.scale.iter_mut().enumerate() {
+                    if let Some(s) = curve.sample(life_fraction(self.age[i], self.life[i])) {
+                        *scale = s * frame.curve_scale;
+                    }
+                }
+            }
+            ParticleAnimation::Expr(channel, expr) => {
+                for i in 0..self.age.len() {
+                    let mut p = self.get(i);
+                    let random = hash_f32(self.seed[i] as u64);
+                    p.set_channel(*channel, expr.eval(&p.expr_vars(random, frame.time), frame.params));
+                    self.set(i, &p);
+                }
+            }
+        }
+    }
+}
+
+
+/// Values shared by every particle animated by expressions this frame.
+pub struct AnimationFrame<'a> {
+    /// Simulated time of the system in seconds.
+    pub time:        f32,
+    /// Values of the parameters used by the expression.
+    pub params:      &'a [f32],
+    /// Multiplier of colors from lifetime curves.
+    pub curve_tint:  [f32; 4],
+    /// Multiplier of scales from lifetime curves.
+    pub curve_scale: f32,
+}
+
+/// Return how far through its lifetime a particle is, from 0 to 1.
+pub(crate) fn life_fraction(age: f32, life: f32) -> f32 {
+    let total = age + life;
+    if total > 0.0 {
+        age / total
+    }
+    else {
+        1.0
+    }
+}
 
 #[repr(C)]
 #[derive(Copy, Clone, Default, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct ParticleInstance {
-    model:  [[f32; 4]; 4],
-    normal: [[f32; 3]; 3],
-    color:  [f32; 4],
+    position: [f32; 3],
+    scale:    f32,
+    /// Rotation quaternion in [s, x, y, z] order.
+    rotation: [f32; 4],
+    /// RGBA color, 8 bits per channel.
+    color:    u32,
+    /// Material of the system the particle belongs to, for custom shaders.
+    material: u32,
 }
 impl ParticleInstance {
     pub fn size() -> u64 {
         mem::size_of::<Self>() as u64
     }
-    const ATTRIBUTES: [wgpu::VertexAttribute; 8] = wgpu::vertex_attr_array![
-        5 => Float32x4,  6 => Float32x4,  7 => Float32x4, 8 => Float32x4,
-        9 => Float32x3, 10 => Float32x3, 11 => Float32x3,
-       12 => Float32x4
+    #[cfg(feature = "wgpu")]
+    const ATTRIBUTES: [wgpu::VertexAttribute; 4] = wgpu::vertex_attr_array![
+        5 => Float32x4, 6 => Float32x4, 7 => Unorm8x4, 8 => Uint32
     ];
 }
+
+/// Pack a color into 8 bit channels, in the order read by Unorm8x4.
+#[cfg(feature = "wgpu")]
+pub fn pack_color(color: Vec4) -> u32 {
+    let channel = |c: f32| (c.clamp(0.0, 1.0) * 255.0).round() as u8;
+    u32::from_le_bytes([
+        channel(color.x),
+        channel(color.y),
+        channel(color.z),
+        channel(color.w),
+    ])
+}
+#[cfg(feature = "wgpu")]
 impl VertexLayout for ParticleInstance {
     fn layout() -> wgpu::VertexBufferLayout<'static> {
         wgpu::VertexBufferLayout {
@@ -134,6 +458,7 @@ impl VertexLayout for ParticleInstance {
     }
 }
 
+#[cfg(feature = "wgpu")]
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Default, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct ParticleVertex {
@@ -141,11 +466,13 @@ pub struct ParticleVertex {
     pub tex_coords: [f32; 2],
     pub normal: [f32; 3],
 }
+#[cfg(feature = "wgpu")]
 impl ParticleVertex {
     const ATTRIBUTES: [wgpu::VertexAttribute; 3] = wgpu::vertex_attr_array![
         0 => Float32x3, 1 => Float32x2, 2 => Float32x3
     ];
 }
+#[cfg(feature = "wgpu")]
 impl VertexLayout for ParticleVertex {
     fn layout() -> wgpu::VertexBufferLayout<'static> {
         wgpu::VertexBufferLayout {
@@ -156,15 +483,65 @@ impl VertexLayout for ParticleVertex {
     }
 }
 
+#[cfg(feature = "wgpu")]
 pub struct ParticleMesh {
     pub vertex_buf:   wgpu::Buffer,
     pub index_buf:    Option<wgpu::Buffer>,
     pub vertex_count: u32,
     pub index_count:  u32,
 }
+#[cfg(feature = "wgpu")]
 impl ParticleMesh {
     pub fn new(device: &wgpu::Device, mesh_type: &ParticleMeshType) -> BrumousResult<Self> {
         read_obj_file(device, mesh_type)
     }
+
+    /// Return number of indices drawn if the mesh is indexed, otherwise vertices.
+    pub fn draw_count(&self) -> u32 {
+        if self.index_buf.is_some() {
+            self.index_count
+        }
+        else {
+            self.vertex_count
+        }
+    }
+}
+
+
+
+#[test]
+fn plane_collision() {
+    let floor = ParticleCollider::Plane {
+        point: Vec3::zero(),
+        normal: Vec3::new(0.0, 1.0, 0.0),
+        restitution: 0.5,
+    };
+    let mut position = Vec3::new(1.0, -0.5, 0.0);
+    let mut velocity = Vec3::new(1.0, -2.0, 0.0);
+
+    assert!(collide(&mut position, &mut velocity, &[floor]));
+    assert!(position == Vec3::new(1.0, 0.0, 0.0));
+    assert!(velocity == Vec3::new(0.5, 1.0, 0.0));
+
+    // Particle is now moving away from the plane.
+    assert!(!collide(&mut position, &mut velocity, &[floor]));
+}
+
+#[test]
+fn swap_remove_compaction() {
+    let mut storage = ParticleStorage::with_capacity(3);
+    for life in [1.0, 2.0, 3.0] {
+        storage.push(Particle { life, ..Default::default() });
+    }
+    storage.swap_remove(0);
+
+    assert!(storage.len() == 2);
+    assert!(storage.life == vec![3.0, 2.0]);
 }
 
+#[cfg(feature = "wgpu")]
+#[test]
+fn instance_size() {
+    assert!(ParticleInstance::size() == 40);
+    assert!(pack_color(Vec4::new(1.0, 0.0, 0.5, 2.0)) == 0xff80_00ff);
+}
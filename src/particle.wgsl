@@ -6,14 +6,13 @@ struct Camera {
 var<uniform> camera: Camera;
 
 struct ParticleInput {
-    @location(5) model_mat_0: vec4<f32>,
-    @location(6) model_mat_1: vec4<f32>,
-    @location(7) model_mat_2: vec4<f32>,
-    @location(8) model_mat_3: vec4<f32>,
-    @location(9) norm_mat_0: vec3<f32>,
-    @location(10) norm_mat_1: vec3<f32>,
-    @location(11) norm_mat_2: vec3<f32>,
-    @location(12) color: vec4<f32>,
+    // xyz: position, w: scale
+    @location(5) position: vec4<f32>,
+    // s, x, y, z
+    @location(6) rotation: vec4<f32>,
+    @location(7) color: vec4<f32>,
+    // Set per system, unused by the built-in shader.
+    @location(8) material: u32,
 };
 
 struct VertexInput {
@@ -44,20 +43,37 @@ struct Light {
 var<storage, read> lights: array<Light>;
 
 
+fn quat_to_mat(q: vec4<f32>) -> mat3x3<f32> {
+    let x2 = q.y + q.y;
+    let y2 = q.z + q.z;
+    let z2 = q.w + q.w;
+    let xx2 = x2 * q.y;
+    let xy2 = x2 * q.z;
+    let xz2 = x2 * q.w;
+    let yy2 = y2 * q.z;
+    let yz2 = y2 * q.w;
+    let zz2 = z2 * q.w;
+    let sx2 = x2 * q.x;
+    let sy2 = y2 * q.x;
+    let sz2 = z2 * q.x;
+    return mat3x3<f32>(
+        vec3<f32>(1.0 - yy2 - zz2, xy2 + sz2, xz2 - sy2),
+        vec3<f32>(xy2 - sz2, 1.0 - xx2 - zz2, yz2 + sx2),
+        vec3<f32>(xz2 + sy2, yz2 - sx2, 1.0 - xx2 - yy2),
+    );
+}
+
 @vertex
 fn vs_main(vertex: VertexInput, particle: ParticleInput) -> VertexOutput {
     var out: VertexOutput;
 
+    let norm_mat = quat_to_mat(particle.rotation);
+    let s = particle.position.w;
     let model_mat = mat4x4<f32>(
-        particle.model_mat_0,
-        particle.model_mat_1,
-        particle.model_mat_2,
-        particle.model_mat_3,
-    );
-    let norm_mat = mat3x3<f32>(
-        particle.norm_mat_0,
-        particle.norm_mat_1,
-        particle.norm_mat_2,
+        vec4<f32>(norm_mat[0] * s, 0.0),
+        vec4<f32>(norm_mat[1] * s, 0.0),
+        vec4<f32>(norm_mat[2] * s, 0.0),
+        vec4<f32>(particle.position.xyz, 1.0),
     );
 
     out.world_pos = (model_mat * vec4<f32>(vertex.pos, 1.0)).xyz;
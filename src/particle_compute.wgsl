@@ -0,0 +1,95 @@
+struct Particle {
+    // xyz: position, w: remaining life
+    position: vec4<f32>,
+    // xyz: velocity, w: age
+    velocity: vec4<f32>,
+    // s, x, y, z
+    rotation: vec4<f32>,
+    color: vec4<f32>,
+    // x: scale, y: mass, w: slot the particle spawns into
+    extra: vec4<f32>,
+};
+
+struct Params {
+    force: vec4<f32>,
+    delta: f32,
+    spawn_count: u32,
+    particle_count: u32,
+    attractor_count: u32,
+    material: u32,
+};
+
+@group(0) @binding(0)
+var<uniform> params: Params;
+@group(0) @binding(1)
+var<storage, read_write> particles: array<Particle>;
+@group(0) @binding(2)
+var<storage, read> spawns: array<Particle>;
+// xyz: position, w: mass
+@group(0) @binding(3)
+var<storage, read> attractors: array<vec4<f32>>;
+// Instances are 10 words: position, scale, rotation, packed color, material.
+// Stored as u32 so the packed color is copied bit for bit.
+@group(0) @binding(4)
+var<storage, read_write> instances: array<u32>;
+
+let G: f32 = 0.00000000006674;
+let INSTANCE_SIZE: u32 = 10u;
+
+
+@compute @workgroup_size(64)
+fn cs_spawn(@builtin(global_invocation_id) id: vec3<u32>) {
+    let i = id.x;
+    if i >= params.spawn_count {
+        return;
+    }
+    let p = spawns[i];
+    particles[bitcast<u32>(p.extra.w)] = p;
+}
+
+@compute @workgroup_size(64)
+fn cs_update(@builtin(global_invocation_id) id: vec3<u32>) {
+    let i = id.x;
+    if i >= params.particle_count {
+        return;
+    }
+    let base = i * INSTANCE_SIZE;
+    let delta = params.delta;
+    var p = particles[i];
+
+    if p.position.w > 0.0 {
+        p.position.w -= delta;
+        p.velocity.w += delta;
+    }
+    if p.position.w <= 0.0 {
+        particles[i] = p;
+        for (var k: u32 = 0u; k < INSTANCE_SIZE; k++) {
+            instances[base + k] = 0u;
+        }
+        return;
+    }
+
+    var vel = p.velocity.xyz;
+    for (var a: u32 = 0u; a < params.attractor_count; a++) {
+        let att = attractors[a];
+        let pa = att.xyz - p.position.xyz;
+        vel += normalize(pa) * (G * att.w * delta / dot(pa, pa));
+    }
+    vel += params.force.xyz * (delta * 0.5 / p.extra.y);
+    let pos = p.position.xyz + vel * delta;
+
+    p.position = vec4<f32>(pos, p.position.w);
+    p.velocity = vec4<f32>(vel, p.velocity.w);
+    particles[i] = p;
+
+    instances[base + 0u] = bitcast<u32>(pos.x);
+    instances[base + 1u] = bitcast<u32>(pos.y);
+    instances[base + 2u] = bitcast<u32>(pos.z);
+    instances[base + 3u] = bitcast<u32>(p.extra.x);
+    instances[base + 4u] = bitcast<u32>(p.rotation.x);
+    instances[base + 5u] = bitcast<u32>(p.rotation.y);
+    instances[base + 6u] = bitcast<u32>(p.rotation.z);
+    instances[base + 7u] = bitcast<u32>(p.rotation.w);
+    instances[base + 8u] = pack4x8unorm(p.color);
+    instances[base + 9u] = params.material;
+}
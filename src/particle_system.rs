@@ -1,80 +1,491 @@
+use std::mem;
 use std::num::NonZeroU64;
 use std::time::Duration;
-use std::collections::VecDeque;
+use std::vec::Drain;
+use std::borrow::Cow;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
 
 use crate::particle::*;
 use crate::random::Randf32;
 use crate::error::BrumousResult;
 use crate::ParticleSystemDescriptor;
 use crate::ParticleSystemBounds;
+use crate::SimulationSpace;
+use crate::SortMode;
+use crate::LodBand;
+use crate::frustum::{Frustum, OffscreenMode};
+use crate::snapshot::{ParticleSystemSnapshot, ParticleSnapshot, AttributeSnapshot};
+use crate::transform::Transform;
+use crate::curve::ParticleCurve;
+use crate::expr::{Expr, ExprVars};
 use crate::vector::{Vec3, Vec4};
+use crate::quaternion::Quaternion;
 
+#[cfg(feature = "wgpu")]
 use wgpu::util::DeviceExt;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
 
 
 pub enum ParticleAnimation {
-    Color(Box<dyn Fn(Vec4, f32) -> Vec4>),
-    Scale(Box<dyn Fn(f32, f32) -> f32>),
+    Color(Box<dyn Fn(Vec4, f32) -> Vec4 + Send + Sync>),
+    Scale(Box<dyn Fn(f32, f32) -> f32 + Send + Sync>),
+    /// Set color from a curve over each particle's lifetime.
+    ColorOverLife(ParticleCurve<[f32; 4]>),
+    /// Set scale from a curve over each particle's lifetime.
+    ScaleOverLife(ParticleCurve<f32>),
+    /// Set a channel of each particle to the value of an expression.
+    Expr(ParticleChannel, Expr),
+}
+
+/// Value of a named parameter, see [`ParticleSystem::set_parameter`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ParticleParameter {
+    Float(f32),
+    Vec3([f32; 3]),
+    Color([f32; 4]),
+}
+impl ParticleParameter {
+    /// Return component of the parameter by name, x, y, z, w or r, g, b, a,
+    /// or the value of a float parameter if the component is empty.
+    pub fn component(&self, component: &str) -> Option<f32> {
+        let idx = match component {
+            "" => return match *self {
+                ParticleParameter::Float(x) => Some(x),
+                _ => None,
+            },
+            "x" | "r" => 0,
+            "y" | "g" => 1,
+            "z" | "b" => 2,
+            "w" | "a" => 3,
+            _ => return None,
+        };
+        match *self {
+            ParticleParameter::Float(_) => None,
+            ParticleParameter::Vec3(v) => v.get(idx).copied(),
+            ParticleParameter::Color(c) => c.get(idx).copied(),
+        }
+    }
+}
+impl From<f32> for ParticleParameter {
+    fn from(x: f32) -> Self {
+        Self::Float(x)
+    }
+}
+impl From<[f32; 3]> for ParticleParameter {
+    fn from(v: [f32; 3]) -> Self {
+        Self::Vec3(v)
+    }
+}
+impl From<[f32; 4]> for ParticleParameter {
+    fn from(c: [f32; 4]) -> Self {
+        Self::Color(c)
+    }
+}
+
+/// Setting of a particle system driven by an expression of its parameters,
+/// see [`ParticleSystem::add_binding`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ParticleBinding {
+    /// Particles emitted per frame, at most the system's max.
+    Rate,
+    RateOverDistance,
+    InheritVelocity,
+    /// Mean of the bounds new particles are picked from. Life and mass
+    /// are kept high enough that no particle spawns dead or without mass.
+    Spawn(ParticleChannel),
+    /// Multiplier of the force at an index of the system's forces.
+    ForceStrength(usize),
+    /// Mass of the attractor at an index of the system's attractors.
+    AttractorMass(usize),
+    /// Multiplier of values from lifetime curves. Only the scale and
+    /// color channels are used.
+    Curve(ParticleChannel),
+}
+
+/// Value of a particle that can be set by an expression.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ParticleChannel {
+    Scale,
+    /// Remaining life in seconds, particles die once it reaches 0.
+    Life,
+    Mass,
+    Red,
+    Green,
+    Blue,
+    Alpha,
+    VelocityX,
+    VelocityY,
+    VelocityZ,
+}
+
+type AttributeInit = Box<dyn Fn(f32) -> ParticleAttribute + Send + Sync>;
+type AttributeUpdate = Box<dyn Fn(ParticleAttribute, f32) -> ParticleAttribute + Send + Sync>;
+
+/// Value of a user-defined per-particle attribute.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ParticleAttribute {
+    F32(f32),
+    Vec3([f32; 3]),
+    Vec4([f32; 4]),
+}
+impl ParticleAttribute {
+    /// Return attribute as a vec4, padding unused components with zero.
+    pub fn to_array(&self) -> [f32; 4] {
+        match *self {
+            ParticleAttribute::F32(x) => [x, 0.0, 0.0, 0.0],
+            ParticleAttribute::Vec3([x, y, z]) => [x, y, z, 0.0],
+            ParticleAttribute::Vec4(v) => v,
+        }
+    }
+}
+
+/// Describes a named attribute stored for every particle.
+///
+/// `init` is called when a particle spawns with a random number in [0, 1),
+/// `update` is called every frame with the current value and frame delta.
+/// Uploaded attributes are written to a separate instance buffer, one vec4
+/// per attribute, starting at shader location 13.
+pub struct ParticleAttributeDescriptor<'a> {
+    pub name:   &'a str,
+    pub init:   AttributeInit,
+    pub update: Option<AttributeUpdate>,
+    pub upload: bool,
+}
+
+struct ParticleAttributeChannel {
+    name:   String,
+    init:   AttributeInit,
+    update: Option<AttributeUpdate>,
+    upload: bool,
+    values: Vec<ParticleAttribute>,
+}
+
+/// Kind of event reported by a particle system.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParticleEventKind {
+    Spawn,
+    Death,
+    Collision,
+}
+
+/// State of a particle at the time an event occurred.
+#[derive(Clone, Copy, Debug)]
+pub struct ParticleEvent {
+    pub kind:     ParticleEventKind,
+    pub index:    usize,
+    pub position: [f32; 3],
+    pub velocity: [f32; 3],
+    pub color:    [f32; 4],
+}
+impl ParticleEvent {
+    fn new(kind: ParticleEventKind, index: usize, particle: &Particle) -> Self {
+        Self {
+            kind,
+            index,
+            position: particle.position.into(),
+            velocity: particle.velocity.into(),
+            color:    particle.color.into(),
+        }
+    }
+}
+
+/// Read-only view of a living particle.
+#[derive(Clone, Copy, Debug)]
+pub struct ParticleView {
+    pub index:    usize,
+    pub position: [f32; 3],
+    pub velocity: [f32; 3],
+    pub color:    [f32; 4],
+    pub scale:    f32,
+    pub age:      f32,
+    pub life:     f32,
+}
+impl ParticleView {
+    pub(crate) fn new(index: usize, particle: &Particle) -> Self {
+        Self {
+            index,
+            position: particle.position.into(),
+            velocity: particle.velocity.into(),
+            color:    particle.color.into(),
+            scale:    particle.scale,
+            age:      particle.age,
+            life:     particle.life,
+        }
+    }
+}
+
+/// Axis-aligned bounding box.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: [f32; 3],
+    pub max: [f32; 3],
+}
+impl Aabb {
+    /// Return smallest box containing all points, or None if there are no points.
+    pub fn from_points<I: IntoIterator<Item = [f32; 3]>>(points: I) -> Option<Self> {
+        let mut points = points.into_iter();
+        let first = points.next()?;
+        let mut aabb = Self { min: first, max: first };
+        for p in points {
+            aabb.extend(p);
+        }
+        Some(aabb)
+    }
+
+    /// Grow box to contain point.
+    pub fn extend(&mut self, p: [f32; 3]) {
+        for (i, x) in p.into_iter().enumerate() {
+            self.min[i] = self.min[i].min(x);
+            self.max[i] = self.max[i].max(x);
+        }
+    }
+
+    /// Return center of box.
+    pub fn center(&self) -> [f32; 3] {
+        [
+            (self.min[0] + self.max[0]) * 0.5,
+            (self.min[1] + self.max[1]) * 0.5,
+            (self.min[2] + self.max[2]) * 0.5,
+        ]
+    }
+}
+
+/// Playback state of a particle system.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PlaybackState {
+    /// Emitting and simulating particles.
+    Playing,
+    /// Simulation is frozen.
+    Paused,
+    /// Not emitting, living particles are still simulated.
+    Stopped,
 }
 
 /// A ParticleSystem manages a set of particles.
 pub struct ParticleSystem {
-    particles:  Vec<Particle>,
-    buf:        wgpu::Buffer,
-    spawnqueue: VecDeque<usize>,
-    rate:       usize,
-    position:   Vec3,
-    name:       String,
-    life:       f32,
-    attractors: Vec<ParticleAttractor>,
-    bounds:     ParticleSystemBounds,
-    forces:     Vec<Vec3>,
-    rand:       Randf32,
-    anims:      Vec<ParticleAnimation>,
-    living:     Vec<Particle>,
-    dummy:      Vec<ParticleInstance>,
+    particles:          ParticleStorage,
+    max:                usize,
+    #[cfg(feature = "wgpu")]
+    gpu:                Option<ParticleBuffers>,
+    rate:               usize,
+    transform:          Transform,
+    parent:             Option<Transform>,
+    space:              SimulationSpace,
+    prev_position:      Vec3,
+    inherit_velocity:   f32,
+    rate_over_distance: f32,
+    emit_carry:         f32,
+    name:               String,
+    life:               f32,
+    duration:           f32,
+    looping:            bool,
+    state:              PlaybackState,
+    attractors:         Vec<ParticleAttractor>,
+    bounds:             ParticleSystemBounds,
+    /// Bounds with the means set by spawn bindings, used when emitting.
+    spawn_bounds:       ParticleSystemBounds,
+    forces:             Vec<Vec3>,
+    colliders:          Vec<ParticleCollider>,
+    events:             Vec<ParticleEvent>,
+    rand:               Randf32,
+    anims:              Vec<ParticleAnimation>,
+    spawn_exprs:        Vec<(ParticleChannel, Expr)>,
+    params:             Vec<(String, ParticleParameter)>,
+    bindings:           Vec<(ParticleBinding, Expr)>,
+    force_scale:        Vec<f32>,
+    attractor_mass:     Vec<Option<f32>>,
+    param_values:       Vec<f32>,
+    curve_tint:         [f32; 4],
+    curve_scale:        f32,
+    bound_emission:     BoundEmission,
+    time:               f32,
+    attributes:         Vec<ParticleAttributeChannel>,
+    sort:               SortMode,
+    view_pos:           Vec3,
+    lod:                Vec<LodBand>,
+    lod_level:          Option<usize>,
+    lod_frames:         u32,
+    lod_delta:          f32,
+    offscreen:          OffscreenMode,
+    visible:            bool,
+    shared:             SharedForces,
+    material:           u32,
+    #[cfg(feature = "wgpu")]
+    merged:             bool,
+    time_scale:         f32,
+    set_time_scale:     f32,
+    global_time_scale:  Option<GlobalTimeScale>,
+    ignore_global_time: bool,
 }
 impl ParticleSystem {
+    #[cfg(feature = "wgpu")]
     pub fn new(
         device: &wgpu::Device,
         sys_desc: &ParticleSystemDescriptor,
     ) -> BrumousResult<Self> {
-        let particles = vec![Particle::default(); sys_desc.max];
-
-        let buf = device.create_buffer_init(
-            &wgpu::util::BufferInitDescriptor {
-                label: Some("Instance Buffer"),
-                contents: bytemuck::cast_slice(&particles.instance()),
-                usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
-            }
-        );
-
-        let spawnqueue = VecDeque::from((0..sys_desc.max).collect::<Vec<usize>>());
+        let mut sys = Self::headless(sys_desc)?;
+        sys.create_buffers(device);
+        Ok(sys)
+    }
 
+    /// Create a system without GPU buffers, which can be simulated but not
+    /// uploaded or drawn, e.g. on a server or in tests without a GPU.
+    /// Fails if the descriptor is invalid, see [`ParticleSystemDescriptor::validate`].
+    pub fn headless(sys_desc: &ParticleSystemDescriptor) -> BrumousResult<Self> {
+        sys_desc.validate()?;
         Ok(
             Self {
-                particles,
-                buf,
-                spawnqueue,
-                rate:       sys_desc.rate,
-                position:   sys_desc.pos,
-                name:       sys_desc.name.to_string(),
-                life:       sys_desc.life,
-                bounds:     sys_desc.bounds,
-                attractors: Vec::new(),
-                forces:     Vec::new(),
-                rand:       Randf32::new(),
-                anims:      Vec::new(),
-                living:     Vec::with_capacity(sys_desc.max),
-                dummy:      vec![ParticleInstance::default(); sys_desc.max],
+                particles:          ParticleStorage::with_capacity(sys_desc.max),
+                max:                sys_desc.max,
+                #[cfg(feature = "wgpu")]
+                gpu:                None,
+                rate:               sys_desc.rate,
+                transform:          Transform {
+                    translation: sys_desc.pos,
+                    rotation:    sys_desc.rotation.normalized(),
+                    scale:       sys_desc.scale,
+                },
+                parent:             None,
+                space:              sys_desc.space,
+                prev_position:      sys_desc.pos,
+                inherit_velocity:   sys_desc.inherit_velocity,
+                rate_over_distance: sys_desc.rate_over_distance,
+                emit_carry:         0.0,
+                name:               sys_desc.name.to_string(),
+                life:               sys_desc.life,
+                duration:           sys_desc.life,
+                looping:            sys_desc.looping,
+                state:              PlaybackState::Playing,
+                bounds:             sys_desc.bounds,
+                spawn_bounds:       sys_desc.bounds,
+                attractors:         Vec::new(),
+                forces:             Vec::new(),
+                colliders:          Vec::new(),
+                events:             Vec::new(),
+                rand:               Randf32::new(),
+                anims:              Vec::new(),
+                spawn_exprs:        Vec::new(),
+                params:             Vec::new(),
+                bindings:           Vec::new(),
+                force_scale:        Vec::new(),
+                attractor_mass:     Vec::new(),
+                param_values:       Vec::new(),
+                curve_tint:         [1.0; 4],
+                curve_scale:        1.0,
+                bound_emission:     BoundEmission::default(),
+                time:               0.0,
+                attributes:         Vec::new(),
+                sort:               sys_desc.sort,
+                view_pos:           Vec3::zero(),
+                lod:                sorted_lod(sys_desc.lod),
+                lod_level:          None,
+                lod_frames:         0,
+                lod_delta:          0.0,
+                offscreen:          sys_desc.offscreen,
+                visible:            true,
+                shared:             SharedForces::default(),
+                material:           sys_desc.material,
+                #[cfg(feature = "wgpu")]
+                merged:             false,
+                time_scale:         1.0,
+                set_time_scale:     1.0,
+                global_time_scale:  None,
+                ignore_global_time: false,
             }
         )
     }
 
-    fn respawn_particles(&mut self, rate: usize) {
-        for _ in 0..rate {
-            if let Some(idx) = self.spawnqueue.pop_front() {
-                self.particles[idx] = Particle::new(&mut self.rand, &self.bounds, &self.position);
+    /// Create or recreate GPU buffers of the system, sized for its max
+    /// particles and uploaded attributes.
+    #[cfg(feature = "wgpu")]
+    pub fn create_buffers(&mut self, device: &wgpu::Device) {
+        self.gpu = Some(ParticleBuffers::new(device, self.max, self.uploaded_attribute_count()));
+    }
+
+    /// Recreate GPU buffers if the max particles or uploaded attributes
+    /// changed since they were created. Systems without buffers are left
+    /// without buffers.
+    #[cfg(feature = "wgpu")]
+    pub fn resize_buffers(&mut self, device: &wgpu::Device) {
+        let attributes = self.uploaded_attribute_count();
+        if let Some(gpu) = &self.gpu {
+            if gpu.capacity != self.max || gpu.attribute_count != attributes {
+                self.create_buffers(device);
+            }
+        }
+    }
+
+    /// Return whether the system has GPU buffers and can be drawn.
+    #[cfg(feature = "wgpu")]
+    pub fn has_buffers(&self) -> bool {
+        self.gpu.is_some()
+    }
+
+    /// Apply settings from a descriptor, e.g. after the effect file it was
+    /// loaded from changed. Living particles, the transform and playback
+    /// state are kept, so the position and rotation of the descriptor are ignored.
+    ///
+    /// If the max particles changed, [`ParticleSystem::resize_buffers`]
+    /// should be called before the system is uploaded again. An invalid
+    /// descriptor is rejected and leaves the system unchanged.
+    pub fn apply_descriptor(&mut self, sys_desc: &ParticleSystemDescriptor) -> BrumousResult<()> {
+        sys_desc.validate()?;
+        self.set_max(sys_desc.max);
+        self.rate = sys_desc.rate;
+        self.rate_over_distance = sys_desc.rate_over_distance;
+        self.space = sys_desc.space;
+        self.inherit_velocity = sys_desc.inherit_velocity;
+        self.name = sys_desc.name.to_string();
+        self.duration = sys_desc.life;
+        self.life = self.life.min(sys_desc.life);
+        self.looping = sys_desc.looping;
+        self.bounds = sys_desc.bounds;
+        self.sort = sys_desc.sort;
+        self.offscreen = sys_desc.offscreen;
+        self.set_lod(sys_desc.lod);
+        self.material = sys_desc.material;
+        Ok(())
+    }
+
+    /// Spawn particles spread evenly along the path the emitter moved
+    /// this frame, so fast moving emitters leave a continuous trail.
+    fn respawn_particles(&mut self, rate: usize, emitter_velocity: Vec3, world: &Transform) {
+        let spawn_params = self.spawn_exprs.iter()
+            .map(|(_, expr)| self.expr_params(expr))
+            .collect::<Vec<Vec<f32>>>();
+        for i in 0..rate {
+            let idx = self.particles.len();
+            if idx < self.live_max() {
+                let t = (i + 1) as f32 / rate as f32;
+                let mut particle = Particle::new(&mut self.rand, &self.spawn_bounds);
+                for ((channel, expr), params) in self.spawn_exprs.iter().zip(spawn_params.iter()) {
+                    let vars = particle.expr_vars(self.rand.next(), self.time);
+                    particle.set_channel(*channel, expr.eval(&vars, params));
+                }
+                if self.space == SimulationSpace::World {
+                    let transform = Transform {
+                        translation: self.prev_position.lerp(world.translation, t),
+                        ..*world
+                    };
+                    particle = particle.transformed(&transform);
+                    let inherit_velocity = self.bound_emission.inherit_velocity.unwrap_or(self.inherit_velocity);
+                    particle.velocity += emitter_velocity * inherit_velocity;
+                }
+                self.particles.push(particle);
+                for attr in self.attributes.iter_mut() {
+                    attr.values.push((attr.init)(self.rand.next()));
+                }
+                let p = to_world(self.space, world, &particle);
+                self.events.push(
+                    ParticleEvent::new(ParticleEventKind::Spawn, idx, &p)
+                );
             }
             else {
                 return;
@@ -82,95 +493,692 @@ impl ParticleSystem {
         }
     }
 
-    /// Spawn new particles and update existing particles, should be called every frame.
+    /// Spawn new particles and update existing particles, then upload them,
+    /// should be called every frame. Same as [`ParticleSystem::simulate`]
+    /// followed by [`ParticleSystem::upload`].
+    ///
+    /// Events from the previous call are discarded, so they should be read
+    /// with [`ParticleSystem::events`] or [`ParticleSystem::drain_events`]
+    /// before the next call.
+    #[cfg(feature = "wgpu")]
     pub fn update(&mut self, delta: Duration, queue: &wgpu::Queue, vp: [f32; 3]) {
-        let view_pos = Vec3::from(vp);
+        self.simulate(delta, vp);
+        self.upload(queue);
+    }
+
+    /// Like [`ParticleSystem::update`], but nothing is uploaded while the
+    /// system is outside the frustum, and it is skipped by the draw calls.
+    /// Whether it is still simulated is set by its [`OffscreenMode`].
+    #[cfg(feature = "wgpu")]
+    pub fn update_culled(
+        &mut self,
+        delta: Duration,
+        queue: &wgpu::Queue,
+        vp: [f32; 3],
+        frustum: &Frustum,
+    ) {
+        self.simulate_culled(delta, vp, frustum);
+        self.upload(queue);
+    }
 
-        let delta = delta.as_millis() as f32 / 1000.0;
-        if self.life >= 0.0 {
-            self.respawn_particles(self.rate);
+    /// Spawn new particles and update existing particles without touching
+    /// the GPU. The view position selects the LOD band and is used to sort
+    /// particles on the next upload.
+    pub fn simulate(&mut self, delta: Duration, vp: [f32; 3]) {
+        self.events.clear();
+        self.view_pos = Vec3::from(vp);
+        self.select_lod(self.view_pos);
+        if self.state != PlaybackState::Paused {
+            self.tick(delta.as_millis() as f32 / 1000.0);
+        }
+        else {
+            self.skip_frame();
         }
-        self.life -= delta;
+        self.visible = true;
+    }
 
-        for (index, particle) in self.particles.iter_mut().enumerate() {
-            particle.life -= delta;
-            if particle.life > 0.0 {
-                particle.update_pos(delta, &self.attractors, &self.forces);
-                particle.cam_dist = (particle.position - view_pos).len();
-                self.living.push(*particle);
-            }
-            else {
-                // Add dead particle to respawn queue if not already queued.
-                if !particle.queued {
-                    self.spawnqueue.push_back(index);
-                    particle.queued = true;
+    /// Like [`ParticleSystem::simulate`], but the system is marked as not
+    /// visible while outside the frustum, so it is not uploaded or drawn.
+    pub fn simulate_culled(&mut self, delta: Duration, vp: [f32; 3], frustum: &Frustum) {
+        self.events.clear();
+        self.view_pos = Vec3::from(vp);
+        self.select_lod(self.view_pos);
+        let simulate = self.state != PlaybackState::Paused;
+        if simulate && self.offscreen == OffscreenMode::Simulate {
+            self.tick(delta.as_millis() as f32 / 1000.0);
+        }
+        self.visible = frustum.intersects(&self.culling_box());
+        if self.visible && simulate && self.offscreen == OffscreenMode::Pause {
+            self.tick(delta.as_millis() as f32 / 1000.0);
+        }
+        else if !simulate || self.offscreen == OffscreenMode::Pause {
+            self.skip_frame();
+        }
+    }
+
+    /// Move the emitter's previous position to where it is now, so that
+    /// movement while the system isn't simulated doesn't spawn a trail or
+    /// add to the velocity of particles once it is simulated again.
+    fn skip_frame(&mut self) {
+        self.prev_position = self.world_transform().translation;
+    }
+
+    /// Set multiplier of time passed to update, 0 freezes the system.
+    pub fn set_time_scale(&mut self, time_scale: f32) {
+        self.time_scale = time_scale.max(0.0);
+    }
+
+    /// Return multiplier of time passed to update.
+    pub fn time_scale(&self) -> f32 {
+        self.time_scale
+    }
+
+    /// Follow a time scale shared with other systems and sets. Systems in
+    /// a set follow the set's instead.
+    pub fn set_global_time_scale(&mut self, time_scale: Option<GlobalTimeScale>) {
+        self.global_time_scale = time_scale;
+    }
+
+    /// Make the system ignore the global time scale, e.g. for UI effects.
+    /// The time scale of the system and its set still apply.
+    pub fn set_ignore_global_time_scale(&mut self, ignore: bool) {
+        self.ignore_global_time = ignore;
+    }
+
+    /// Return product of the system, set and global time scales.
+    pub fn effective_time_scale(&self) -> f32 {
+        let global = match &self.global_time_scale {
+            Some(time_scale) if !self.ignore_global_time => time_scale.get(),
+            _ => 1.0,
+        };
+        self.time_scale * self.set_time_scale * global
+    }
+
+    /// Pick the LOD band for the distance between the emitter and view position.
+    fn select_lod(&mut self, view_pos: Vec3) {
+        let dist = (self.world_transform().translation - view_pos).len();
+        self.lod_level = self.lod.iter().rposition(|band| dist >= band.distance);
+    }
+
+    fn lod_band(&self) -> LodBand {
+        self.lod_level.map_or(LodBand::default(), |i| self.lod[i])
+    }
+
+    /// Max living particles, reduced by the current LOD band.
+    fn live_max(&self) -> usize {
+        (self.max as f32 * self.lod_band().max.clamp(0.0, 1.0)) as usize
+    }
+
+    /// Simulate once every `interval` frames of the current LOD band,
+    /// with the scaled time accumulated since the last simulated frame.
+    fn tick(&mut self, delta: f32) {
+        let time_scale = self.effective_time_scale();
+        self.lod_delta += delta * time_scale;
+        self.lod_frames += 1;
+        if self.lod_frames >= self.lod_band().interval {
+            self.step(self.lod_delta, time_scale);
+            self.lod_delta = 0.0;
+            self.lod_frames = 0;
+        }
+    }
+
+    /// Advance simulation by delta seconds. Emission per frame is scaled by
+    /// time_scale, so slowed down systems emit fewer particles per frame.
+    fn step(&mut self, delta: f32, time_scale: f32) {
+        self.apply_bindings();
+        let world = self.world_transform();
+        let moved = world.translation - self.prev_position;
+        let emitter_velocity = if delta > 0.0 {
+            moved / delta
+        }
+        else {
+            Vec3::zero()
+        };
+
+        // A frozen system doesn't emit, even while the emitter moves.
+        if self.state == PlaybackState::Playing && time_scale > 0.0 {
+            let rate_over_distance = self.bound_emission.rate_over_distance.unwrap_or(self.rate_over_distance);
+            let emitted = (self.rate() as f32 * time_scale + rate_over_distance * moved.len())
+                * self.lod_band().rate
+                + self.emit_carry;
+            self.emit_carry = emitted.fract();
+            self.respawn_particles(emitted as usize, emitter_velocity, &world);
+            self.life -= delta;
+            if self.life < 0.0 {
+                if self.looping && self.duration > 0.0 {
+                    self.life += self.duration;
+                }
+                else {
+                    self.state = PlaybackState::Stopped;
                 }
             }
         }
+        self.prev_position = world.translation;
 
-        self.living.sort_by(|p1, p2| p2.cam_dist.partial_cmp(&p1.cam_dist).unwrap());
-        queue.write_buffer(
-            &self.buf,
-            0,
-            bytemuck::cast_slice(&self.living.instance())
+        self.time += delta;
+        self.particles.age(delta);
+        self.remove_dead(&world);
+
+        let own_attractors = self.attractors.iter()
+            .enumerate()
+            .map(|(i, att)| match self.attractor_mass.get(i).copied().flatten() {
+                Some(mass) => ParticleAttractor { mass, ..*att },
+                None => *att,
+            })
+            .collect::<Vec<ParticleAttractor>>();
+        let attractors = combine(&own_attractors, &self.shared.attractors);
+        let own_forces = self.forces.iter()
+            .enumerate()
+            .map(|(i, &force)| force * self.force_scale.get(i).copied().unwrap_or(1.0))
+            .collect::<Vec<Vec3>>();
+        let forces = combine(&own_forces, &self.shared.forces);
+        let colliders = combine(&self.colliders, &self.shared.colliders);
+        let anim_params = self.anims.iter()
+            .map(|anim| match anim {
+                ParticleAnimation::Expr(_, expr) => self.expr_params(expr),
+                _ => Vec::new(),
+            })
+            .collect::<Vec<Vec<f32>>>();
+        let ctx = StepContext {
+            delta,
+            time:        self.time,
+            curve_tint:  self.curve_tint,
+            curve_scale: self.curve_scale,
+            world:       &world,
+            space:       self.space,
+            attractors:  &attractors,
+            forces:      &forces,
+            colliders:   &colliders,
+            anims:       &self.anims,
+            anim_params: &anim_params,
+        };
+        let chunk_size = chunk_size(self.particles.len());
+        let mut attr_chunks = self.attributes.iter_mut()
+            .map(|attr| (&attr.update, attr.values.chunks_mut(chunk_size)))
+            .collect::<Vec<_>>();
+
+        let mut chunks = Vec::new();
+        for (c, particles) in self.particles.chunks_mut(chunk_size).into_iter().enumerate() {
+            let attrs = attr_chunks.iter_mut()
+                .map(|(update, values)| (*update, values.next().unwrap()))
+                .collect::<Vec<_>>();
+            chunks.push((c * chunk_size, particles, attrs));
+        }
+
+        #[cfg(not(feature = "parallel"))]
+        let outputs = chunks.into_iter()
+            .map(|(offset, mut particles, mut attrs)| {
+                step_particles(&ctx, offset, &mut particles, &mut attrs)
+            })
+            .collect::<Vec<Vec<ParticleEvent>>>();
+
+        #[cfg(feature = "parallel")]
+        let outputs = chunks.into_par_iter()
+            .map(|(offset, mut particles, mut attrs)| {
+                step_particles(&ctx, offset, &mut particles, &mut attrs)
+            })
+            .collect::<Vec<Vec<ParticleEvent>>>();
+
+        for events in outputs.into_iter() {
+            self.events.extend(events);
+        }
+    }
+
+    /// Remove particles whose life has run out, keeping storage packed.
+    fn remove_dead(&mut self, world: &Transform) {
+        let mut i = 0;
+        while i < self.particles.len() {
+            if self.particles.life[i] > 0.0 {
+                i += 1;
+                continue;
+            }
+            let p = to_world(self.space, world, &self.particles.get(i));
+            self.events.push(
+                ParticleEvent::new(ParticleEventKind::Death, i, &p)
+            );
+            self.particles.swap_remove(i);
+            for attr in self.attributes.iter_mut() {
+                attr.values.swap_remove(i);
+            }
+        }
+    }
+
+    /// Sort visible particles and write them to the GPU buffers. Systems
+    /// without buffers are skipped. If more particles are alive than the
+    /// buffers hold, only the first ones in draw order are uploaded.
+    #[cfg(feature = "wgpu")]
+    pub fn upload(&mut self, queue: &wgpu::Queue) {
+        if !self.visible {
+            return;
+        }
+        let world = self.world_transform();
+        let Some(gpu) = &mut self.gpu else {
+            return;
+        };
+        let view_pos = self.view_pos;
+        let alive = self.particles.len();
+        let count = alive.min(gpu.capacity);
+        gpu.count = count;
+        // The vertex or index count in front of it is written at draw time,
+        // when the renderer's mesh is known.
+        queue.write_buffer(&gpu.indirect, 4, bytemuck::cast_slice(&[count as u32]));
+        if count == 0 || self.merged {
+            return;
+        }
+
+        gpu.order.extend(0..alive);
+        match self.sort {
+            SortMode::None => {}
+            SortMode::BackToFront | SortMode::FrontToBack => {
+                match self.space {
+                    SimulationSpace::World => {
+                        gpu.cam_dist.extend(
+                            self.particles.position.iter().map(|pos| (*pos - view_pos).len())
+                        );
+                    }
+                    SimulationSpace::Local => {
+                        gpu.cam_dist.extend(
+                            self.particles.position.iter()
+                                .map(|pos| (world.transform_point(*pos) - view_pos).len())
+                        );
+                    }
+                }
+                let cam_dist = &gpu.cam_dist;
+                if self.sort == SortMode::BackToFront {
+                    gpu.order.sort_by(|&i1, &i2| {
+                        cam_dist[i2].total_cmp(&cam_dist[i1])
+                    });
+                }
+                else {
+                    gpu.order.sort_by(|&i1, &i2| {
+                        cam_dist[i1].total_cmp(&cam_dist[i2])
+                    });
+                }
+            }
+            SortMode::ByAge => {
+                let age = &self.particles.age;
+                gpu.order.sort_by(|&i1, &i2| {
+                    age[i2].total_cmp(&age[i1])
+                });
+            }
+        }
+        gpu.order.truncate(count);
+        let instances = instance_particles(
+            self.space,
+            &world,
+            &self.particles,
+            &gpu.order,
+            self.material,
         );
-        let rem = &self.dummy[self.living.len()..self.particles.len()];
         queue.write_buffer(
-            &self.buf,
-            self.living.len() as u64 * ParticleInstance::size(),
-            bytemuck::cast_slice(&rem)
+            &gpu.instances,
+            0,
+            bytemuck::cast_slice(&instances)
         );
-        self.living.clear();
+
+        let attributes = gpu.attributes.as_ref()
+            .filter(|_| gpu.attribute_count == self.attributes.iter().filter(|attr| attr.upload).count());
+        if let Some(attr_buf) = attributes {
+            for &i in gpu.order.iter() {
+                for attr in self.attributes.iter().filter(|attr| attr.upload) {
+                    gpu.attr_data.push(attr.values[i].to_array());
+                }
+            }
+            queue.write_buffer(attr_buf, 0, bytemuck::cast_slice(&gpu.attr_data));
+            gpu.attr_data.clear();
+        }
+        gpu.cam_dist.clear();
+        gpu.order.clear();
+    }
+
+    /// Resume a paused system, or start emitting again if the system is stopped.
+    pub fn play(&mut self) {
+        if self.state == PlaybackState::Stopped {
+            self.life = self.duration;
+        }
+        self.state = PlaybackState::Playing;
+    }
+
+    /// Freeze simulation, living particles are still drawn.
+    pub fn pause(&mut self) {
+        self.state = PlaybackState::Paused;
+    }
+
+    /// Stop emitting new particles, living particles continue until they die.
+    pub fn stop(&mut self) {
+        self.state = PlaybackState::Stopped;
+    }
+
+    /// Stop emitting new particles and kill all living particles.
+    pub fn stop_and_clear(&mut self) {
+        self.stop();
+        self.clear();
+    }
+
+    /// Kill all living particles and start emitting from the beginning.
+    pub fn restart(&mut self) {
+        self.clear();
+        self.life = self.duration;
+        self.state = PlaybackState::Playing;
+    }
+
+    /// Simulate the given number of seconds at 60 steps per second, e.g. so
+    /// a smoke column is already full when it first appears.
+    pub fn prewarm(&mut self, secs: f32) {
+        let step = 1.0 / 60.0;
+        let mut elapsed = 0.0;
+        while elapsed < secs && self.state == PlaybackState::Playing {
+            self.step(step, 1.0);
+            elapsed += step;
+        }
+        self.events.clear();
+    }
+
+    /// Return current playback state.
+    pub fn playback_state(&self) -> PlaybackState {
+        self.state
+    }
+
+    /// Set whether emission restarts after the duration has elapsed.
+    pub fn set_looping(&mut self, looping: bool) {
+        self.looping = looping;
+    }
+
+    /// Set how long the system emits particles for, in seconds.
+    pub fn set_duration(&mut self, duration: f32) {
+        self.duration = duration;
+    }
+
+    fn clear(&mut self) {
+        self.particles.truncate(0);
+        for attr in self.attributes.iter_mut() {
+            attr.values.clear();
+        }
+    }
+
+    /// Return full simulation state, which can be saved and later applied
+    /// with [`ParticleSystem::restore`].
+    pub fn snapshot(&self) -> ParticleSystemSnapshot {
+        ParticleSystemSnapshot {
+            particles:     (0..self.particles.len())
+                .map(|i| ParticleSnapshot::new(&self.particles.get(i)))
+                .collect(),
+            attributes:    self.attributes.iter()
+                .map(|attr| AttributeSnapshot {
+                    name:   attr.name.clone(),
+                    values: attr.values.clone(),
+                })
+                .collect(),
+            rand_state:    self.rand.state(),
+            life:          self.life,
+            state:         self.state,
+            position:      self.transform.translation.into(),
+            rotation:      self.transform.rotation.into(),
+            scale:         self.transform.scale.into(),
+            prev_position: self.prev_position.into(),
+            emit_carry:    self.emit_carry,
+            time:          self.time,
+            lod_frames:    self.lod_frames,
+            lod_delta:     self.lod_delta,
+            attractors:    self.attractors.iter()
+                .map(|att| (att.pos.into(), att.mass))
+                .collect(),
+            forces:        self.forces.iter().map(|&force| force.into()).collect(),
+        }
+    }
+
+    /// Replace simulation state with a snapshot. Particles beyond the max
+    /// are dropped, and attributes missing from the snapshot are reset to
+    /// their initial value.
+    pub fn restore(&mut self, snapshot: &ParticleSystemSnapshot) {
+        self.clear();
+        for p in snapshot.particles.iter().take(self.max) {
+            self.particles.push(p.particle());
+        }
+        let count = self.particles.len();
+        for attr in self.attributes.iter_mut() {
+            if let Some(snap) = snapshot.attributes.iter().find(|snap| snap.name == attr.name) {
+                attr.values.extend(snap.values.iter().take(count));
+            }
+            let init = (attr.init)(0.0);
+            attr.values.resize(count, init);
+        }
+        self.rand = Randf32::from_state(snapshot.rand_state);
+        self.life = snapshot.life;
+        self.state = snapshot.state;
+        self.transform = Transform {
+            translation: snapshot.position.into(),
+            rotation:    snapshot.rotation.into(),
+            scale:       snapshot.scale.into(),
+        };
+        self.prev_position = snapshot.prev_position.into();
+        self.emit_carry = snapshot.emit_carry;
+        self.time = snapshot.time;
+        self.lod_frames = snapshot.lod_frames;
+        self.lod_delta = snapshot.lod_delta;
+        self.attractors = snapshot.attractors.iter()
+            .map(|&(pos, mass)| ParticleAttractor::new(pos, mass))
+            .collect();
+        self.forces = snapshot.forces.iter().map(|&force| force.into()).collect();
+        self.events.clear();
+    }
+
+    /// Return events that occurred during the last update. Particle indices
+    /// refer to the particle's position in storage when the event occurred,
+    /// which changes as dead particles are removed.
+    pub fn events(&self) -> &[ParticleEvent] {
+        &self.events
+    }
+
+    /// Remove and return events that occurred during the last update.
+    pub fn drain_events(&mut self) -> Drain<'_, ParticleEvent> {
+        self.events.drain(..)
     }
 
-    /// Return number of particles in particle system.
+    /// Return maximum number of particles in particle system.
     pub fn particle_count(&self) -> u32 {
-        self.particles.len() as u32
+        self.max as u32
+    }
+
+    /// Return number of living particles in particle system. Only this
+    /// many instances are uploaded, so this is the number drawn.
+    pub fn alive_count(&self) -> usize {
+        self.particles.len()
+    }
+
+    /// Set material index written to every instance, read by custom shaders
+    /// to tell apart particles of different systems drawn in one call.
+    pub fn set_material(&mut self, material: u32) {
+        self.material = material;
+    }
+
+    /// Set order particles are drawn in.
+    pub fn set_sort_mode(&mut self, sort: SortMode) {
+        self.sort = sort;
+    }
+
+    /// Return order particles are drawn in.
+    pub fn sort_mode(&self) -> SortMode {
+        self.sort
+    }
+
+    /// Return iterator over living particles.
+    pub fn particles(&self) -> impl Iterator<Item = ParticleView> + '_ {
+        let world = self.world_transform();
+        (0..self.particles.len())
+            .map(move |i| ParticleView::new(i, &to_world(self.space, &world, &self.particles.get(i))))
+    }
+
+    /// Return bounding box of living particle positions, or None if no particles are alive.
+    pub fn bounding_box(&self) -> Option<Aabb> {
+        Aabb::from_points(self.particles().map(|p| p.position))
+    }
+
+    /// Return conservative box used for culling. It contains the emitter
+    /// and every living particle, padded by the size of the largest particle.
+    pub fn culling_box(&self) -> Aabb {
+        let world = self.world_transform();
+        let pad = 2.0 * self.particles.scale.iter().fold(0.0f32, |max, &s| max.max(s.abs()));
+        let mut aabb = self.bounding_box()
+            .unwrap_or(Aabb { min: world.translation.into(), max: world.translation.into() });
+        aabb.extend(world.translation.into());
+        for i in 0..3 {
+            aabb.min[i] -= pad;
+            aabb.max[i] += pad;
+        }
+        aabb
+    }
+
+    /// Return false if the last call to [`ParticleSystem::update_culled`]
+    /// found the system outside the frustum.
+    pub fn is_visible(&self) -> bool {
+        self.visible
+    }
+
+    /// Set what the system does while outside the frustum.
+    pub fn set_offscreen_mode(&mut self, mode: OffscreenMode) {
+        self.offscreen = mode;
+    }
+
+    /// Return name of particle system.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Return reference to particle buffer, or None if the system is headless.
+    #[cfg(feature = "wgpu")]
+    pub fn particle_buf(&self) -> Option<&wgpu::Buffer> {
+        self.gpu.as_ref().map(|gpu| &gpu.instances)
+    }
+
+    /// Return reference to indirect draw args buffer. The instance count is
+    /// written every upload, the vertex or index count by the indirect draw.
+    #[cfg(feature = "wgpu")]
+    pub fn indirect_buf(&self) -> Option<&wgpu::Buffer> {
+        self.gpu.as_ref().map(|gpu| &gpu.indirect)
+    }
+
+    /// Return number of instances written by the last upload.
+    #[cfg(feature = "wgpu")]
+    pub fn instance_count(&self) -> u32 {
+        self.gpu.as_ref().map_or(0, |gpu| gpu.count as u32)
+    }
+
+    /// Set distance bands reducing emission, particle count and simulation
+    /// frequency as the system gets further from the view position.
+    pub fn set_lod(&mut self, bands: &[LodBand]) {
+        self.lod = sorted_lod(bands);
+        self.lod_level = None;
+    }
+
+    /// Return index of the current LOD band, or None if closer than every band.
+    pub fn lod_level(&self) -> Option<usize> {
+        self.lod_level
+    }
+
+    /// Return index into the renderer's LOD meshes to draw the system with,
+    /// or None to use its main mesh.
+    pub fn lod_mesh(&self) -> Option<usize> {
+        self.lod_band().mesh
     }
 
-    /// Return reference to particle buffer.
-    pub fn particle_buf(&self) -> &wgpu::Buffer {
-        &self.buf
+    /// Return reference to custom attribute buffer, if any attributes are
+    /// uploaded and the buffer was resized since they were added.
+    #[cfg(feature = "wgpu")]
+    pub fn attribute_buf(&self) -> Option<&wgpu::Buffer> {
+        self.gpu.as_ref()
+            .filter(|gpu| gpu.attribute_count == self.uploaded_attribute_count())
+            .and_then(|gpu| gpu.attributes.as_ref())
     }
 
     /// Return particle buffer size in bytes.
     pub fn particle_buf_size(&self) -> Option<NonZeroU64> {
-        NonZeroU64::new(self.particles.len() as u64 * ParticleInstance::size())
+        NonZeroU64::new(self.max as u64 * ParticleInstance::size())
     }
 
-    /// Set max number of particles.
+    /// Set max number of particles and resize the GPU buffers. If the new
+    /// max is lower than the number of living particles, the extra
+    /// particles are removed.
+    #[cfg(feature = "wgpu")]
     pub fn set_max_particles(&mut self, new_max: usize, device: &wgpu::Device) {
-        let old_max = self.particles.len();
-        if new_max == old_max {
-            return;
-        }
-        self.particles.resize(new_max, Particle::default());
+        self.set_max(new_max);
+        self.resize_buffers(device);
+    }
 
-        if new_max < old_max {
-            // If max particles is decreased, existing indices in the spawn
-            // queue larger than the new max particles will be invalidated.
-            self.spawnqueue.retain(|i| *i < new_max);
+    /// Set max number of particles without resizing the GPU buffers, see
+    /// [`ParticleSystem::resize_buffers`].
+    pub fn set_max(&mut self, new_max: usize) {
+        self.max = new_max;
+        self.particles.truncate(new_max);
+        for attr in self.attributes.iter_mut() {
+            attr.values.truncate(new_max);
         }
-        else {
-            // If max particles is increased, add new indices to spawn queue.
-            let diff = new_max - old_max;
-            for i in 0..diff {
-                self.spawnqueue.push_back(old_max + i);
-            }
-        }
-        
-        self.buf = device.create_buffer_init(
-            &wgpu::util::BufferInitDescriptor {
-                label: Some("Particle Buffer"),
-                contents: bytemuck::cast_slice(&self.particles.instance()),
-                usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
-            }
-        );
     }
 
-    /// Set position of particle system.
+    /// Set position of particle system. Particles spawned next frame are
+    /// spread between the old and new positions.
     pub fn set_position(&mut self, position: [f32; 3]) {
-        self.position = position.into();
+        self.transform.translation = position.into();
+    }
+
+    /// Move particle system without spawning particles along the way.
+    pub fn teleport(&mut self, position: [f32; 3]) {
+        self.transform.translation = position.into();
+        self.prev_position = self.world_transform().translation;
+    }
+
+    /// Set rotation of particle system as a quaternion in [s, x, y, z] order.
+    pub fn set_rotation(&mut self, rotation: [f32; 4]) {
+        self.transform.rotation = Quaternion::from(rotation).normalized();
+    }
+
+    /// Set scale of particle system, which scales the spawn area and initial velocities.
+    pub fn set_scale(&mut self, scale: [f32; 3]) {
+        self.transform.scale = scale.into();
+    }
+
+    /// Set translation, rotation and scale of particle system.
+    pub fn set_transform(&mut self, transform: Transform) {
+        self.transform = transform;
+    }
+
+    /// Return transform of particle system relative to its parent.
+    pub fn transform(&self) -> Transform {
+        self.transform
+    }
+
+    /// Set transform the particle system's own transform is relative to,
+    /// e.g. a bone or a moving prop the effect is attached to.
+    pub fn set_parent_transform(&mut self, parent: Option<Transform>) {
+        self.parent = parent;
+    }
+
+    /// Return parent transform of particle system.
+    pub fn parent_transform(&self) -> Option<Transform> {
+        self.parent
+    }
+
+    /// Return transform of particle system in world space.
+    pub fn world_transform(&self) -> Transform {
+        match self.parent {
+            Some(parent) => parent * self.transform,
+            None => self.transform,
+        }
+    }
+
+    /// Set whether particles are simulated in world space or in the emitter's local space.
+    pub fn set_simulation_space(&mut self, space: SimulationSpace) {
+        self.space = space;
+    }
+
+    /// Set fraction of emitter velocity added to the velocity of new particles.
+    pub fn set_inherit_velocity(&mut self, inherit_velocity: f32) {
+        self.inherit_velocity = inherit_velocity;
+    }
+
+    /// Set number of particles spawned per unit of distance the emitter moves.
+    pub fn set_rate_over_distance(&mut self, rate_over_distance: f32) {
+        self.rate_over_distance = rate_over_distance;
     }
 
     /// Set number of particles spawned per frame.
@@ -178,6 +1186,12 @@ impl ParticleSystem {
         self.rate = rate;
     }
 
+    /// Return number of particles spawned per frame, as set by a rate
+    /// binding if there is one.
+    pub fn rate(&self) -> usize {
+        self.bound_emission.rate.unwrap_or(self.rate)
+    }
+
     /// Set name of particle system.
     pub fn set_name(&mut self, name: String) {
         self.name = name;
@@ -221,44 +1235,1347 @@ impl ParticleSystem {
         self.attractors.push(ParticleAttractor::new(pos, mass));
     }
 
-    pub fn add_animation(&mut self, anim: ParticleAnimation) {
-        self.anims.push(anim);
+    /// Add an infinite plane that particles bounce off of.
+    pub fn add_plane_collider(&mut self, point: [f32; 3], normal: [f32; 3], restitution: f32) {
+        self.colliders.push(ParticleCollider::plane(point, normal, restitution));
     }
-}
 
-pub struct ParticleAttractor {
-    pub pos: Vec3,
-    pub mass: f32,
-}
-impl ParticleAttractor {
-    fn new(pos: [f32; 3], mass: f32) -> Self {
-        Self { 
-            pos: pos.into(), 
-            mass 
-        }
+    /// Add a sphere that particles bounce off of.
+    pub fn add_sphere_collider(&mut self, center: [f32; 3], radius: f32, restitution: f32) {
+        self.colliders.push(ParticleCollider::sphere(center, radius, restitution));
     }
-}
 
-pub struct ParticleSystemSet(pub Vec<ParticleSystem>);
+    pub fn add_animation(&mut self, anim: ParticleAnimation) {
+        self.anims.push(anim);
+    }
+
+    /// Set a channel of each new particle to the value of an expression,
+    /// evaluated after the particle's initial values are picked from the
+    /// system's bounds and before it is moved to the emitter.
+    pub fn add_spawn_expr(&mut self, channel: ParticleChannel, expr: Expr) {
+        self.spawn_exprs.push((channel, expr));
+    }
+
+    pub fn clear_spawn_exprs(&mut self) {
+        self.spawn_exprs.clear();
+    }
+
+    /// Set value of a named parameter used by expressions and bindings.
+    /// Vector and color parameters are read by component, e.g. `wind.x`
+    /// or `team_color.r`.
+    pub fn set_parameter<P: Into<ParticleParameter>>(&mut self, name: &str, value: P) {
+        let value = value.into();
+        match self.params.iter_mut().find(|(param, _)| param == name) {
+            Some((_, v)) => *v = value,
+            None => self.params.push((name.to_string(), value)),
+        }
+    }
+
+    /// Set value of a named parameter only if it has not been set, so
+    /// defaults can be declared without replacing values set at runtime.
+    pub fn declare_parameter<P: Into<ParticleParameter>>(&mut self, name: &str, value: P) {
+        if self.parameter(name).is_none() {
+            self.set_parameter(name, value);
+        }
+    }
+
+    /// Return value of a named parameter, or None if it has not been set.
+    pub fn parameter(&self, name: &str) -> Option<ParticleParameter> {
+        self.params.iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| *value)
+    }
+
+    /// Return names and values of all parameters.
+    pub fn parameters(&self) -> &[(String, ParticleParameter)] {
+        &self.params
+    }
+
+    /// Drive a setting of the system with an expression of its parameters,
+    /// evaluated every simulated frame before particles are emitted.
+    /// Variables other than `time` are 0. Bound settings return to the
+    /// system's own once the bindings are cleared.
+    pub fn add_binding(&mut self, binding: ParticleBinding, expr: Expr) {
+        self.bindings.push((binding, expr));
+    }
+
+    pub fn clear_bindings(&mut self) {
+        self.bindings.clear();
+        self.spawn_bounds = self.bounds;
+        self.force_scale.clear();
+        self.attractor_mass.clear();
+        self.curve_tint = [1.0; 4];
+        self.curve_scale = 1.0;
+        self.bound_emission = BoundEmission::default();
+    }
+
+    /// Return value of a parameter as used by expressions.
+    fn param_value(&self, name: &str) -> Option<f32> {
+        match self.parameter(name) {
+            Some(param) => param.component(""),
+            None => {
+                let (name, component) = name.rsplit_once('.')?;
+                self.parameter(name)?.component(component)
+            }
+        }
+    }
+
+    /// Return values of the parameters used by an expression, 0 if unset.
+    fn expr_params(&self, expr: &Expr) -> Vec<f32> {
+        expr.params().iter()
+            .map(|name| self.param_value(name).unwrap_or(0.0))
+            .collect()
+    }
+
+    /// Evaluate bindings. Bound values override the system's own for this
+    /// frame only, so those are restored once the bindings are cleared.
+    fn apply_bindings(&mut self) {
+        // Base bounds may have changed since the last frame.
+        self.spawn_bounds = self.bounds;
+        if self.bindings.is_empty() {
+            return;
+        }
+        self.force_scale.clear();
+        self.attractor_mass.clear();
+        self.curve_tint = [1.0; 4];
+        self.curve_scale = 1.0;
+        self.bound_emission = BoundEmission::default();
+
+        let vars = ExprVars { time: self.time, ..Default::default() };
+        let mut params = mem::take(&mut self.param_values);
+        for i in 0..self.bindings.len() {
+            let (binding, expr) = &self.bindings[i];
+            let binding = *binding;
+            params.clear();
+            params.extend(expr.params().iter().map(|name| self.param_value(name).unwrap_or(0.0)));
+            let value = expr.eval(&vars, &params);
+            match binding {
+                ParticleBinding::Rate => {
+                    self.bound_emission.rate = Some((value.max(0.0) as usize).min(self.max));
+                }
+                ParticleBinding::RateOverDistance => {
+                    self.bound_emission.rate_over_distance = Some(value.max(0.0));
+                }
+                ParticleBinding::InheritVelocity => self.bound_emission.inherit_velocity = Some(value),
+                ParticleBinding::Spawn(channel) => {
+                    let bounds = &mut self.spawn_bounds;
+                    // Keep the spawned life positive and mass above zero.
+                    let value = match channel {
+                        ParticleChannel::Life => value.max(bounds.life.1.abs()),
+                        ParticleChannel::Mass => value.max(bounds.mass.1.abs() + f32::EPSILON),
+                        _ => value,
+                    };
+                    let mean = match channel {
+                        ParticleChannel::Scale => &mut bounds.scale.0,
+                        ParticleChannel::Life => &mut bounds.life.0,
+                        ParticleChannel::Mass => &mut bounds.mass.0,
+                        ParticleChannel::Red => &mut bounds.color[0].0,
+                        ParticleChannel::Green => &mut bounds.color[1].0,
+                        ParticleChannel::Blue => &mut bounds.color[2].0,
+                        ParticleChannel::Alpha => &mut bounds.color[3].0,
+                        ParticleChannel::VelocityX => &mut bounds.velocity[0].0,
+                        ParticleChannel::VelocityY => &mut bounds.velocity[1].0,
+                        ParticleChannel::VelocityZ => &mut bounds.velocity[2].0,
+                    };
+                    *mean = value;
+                }
+                ParticleBinding::ForceStrength(idx) => {
+                    if self.force_scale.len() <= idx {
+                        self.force_scale.resize(idx + 1, 1.0);
+                    }
+                    self.force_scale[idx] = value;
+                }
+                ParticleBinding::AttractorMass(idx) => {
+                    if self.attractor_mass.len() <= idx {
+                        self.attractor_mass.resize(idx + 1, None);
+                    }
+                    self.attractor_mass[idx] = Some(value);
+                }
+                ParticleBinding::Curve(channel) => match channel {
+                    ParticleChannel::Scale => self.curve_scale = value,
+                    ParticleChannel::Red => self.curve_tint[0] = value,
+                    ParticleChannel::Green => self.curve_tint[1] = value,
+                    ParticleChannel::Blue => self.curve_tint[2] = value,
+                    ParticleChannel::Alpha => self.curve_tint[3] = value,
+                    _ => {}
+                },
+            }
+        }
+        self.param_values = params;
+    }
+
+    /// Remove all forces and attractors added to the system.
+    pub fn clear_forces(&mut self) {
+        self.forces.clear();
+        self.attractors.clear();
+    }
+
+    pub fn clear_colliders(&mut self) {
+        self.colliders.clear();
+    }
+
+    pub fn clear_animations(&mut self) {
+        self.anims.clear();
+    }
+
+    /// Add a named per-particle attribute, replacing any attribute with the
+    /// same name, and resize the GPU buffers.
+    #[cfg(feature = "wgpu")]
+    pub fn add_attribute(&mut self, device: &wgpu::Device, desc: ParticleAttributeDescriptor) {
+        self.insert_attribute(desc);
+        self.resize_buffers(device);
+    }
+
+    /// Add a named per-particle attribute without resizing the GPU buffers,
+    /// see [`ParticleSystem::resize_buffers`].
+    pub fn insert_attribute(&mut self, desc: ParticleAttributeDescriptor) {
+        self.attributes.retain(|attr| attr.name != desc.name);
+        let mut values = Vec::with_capacity(self.max);
+        values.resize(self.particles.len(), (desc.init)(0.0));
+        self.attributes.push(
+            ParticleAttributeChannel {
+                name:   desc.name.to_string(),
+                init:   desc.init,
+                update: desc.update,
+                upload: desc.upload,
+                values,
+            }
+        );
+    }
+
+    /// Return value of named attribute for particle at index.
+    pub fn attribute(&self, name: &str, index: usize) -> Option<ParticleAttribute> {
+        self.attributes.iter()
+            .find(|attr| attr.name == name)
+            .and_then(|attr| attr.values.get(index).copied())
+    }
+
+    /// Set value of named attribute for particle at index.
+    pub fn set_attribute(&mut self, name: &str, index: usize, value: ParticleAttribute) {
+        if let Some(attr) = self.attributes.iter_mut().find(|attr| attr.name == name) {
+            if let Some(v) = attr.values.get_mut(index) {
+                *v = value;
+            }
+        }
+    }
+
+    /// Return number of attributes uploaded to the attribute buffer.
+    pub fn uploaded_attribute_count(&self) -> usize {
+        self.attributes.iter().filter(|attr| attr.upload).count()
+    }
+}
+
+/// GPU buffers of a particle system, with scratch space used while uploading.
+#[cfg(feature = "wgpu")]
+struct ParticleBuffers {
+    instances:       wgpu::Buffer,
+    indirect:        wgpu::Buffer,
+    attributes:      Option<wgpu::Buffer>,
+    /// Number of particles the buffers hold.
+    capacity:        usize,
+    /// Number of vec4s per particle in the attribute buffer.
+    attribute_count: usize,
+    /// Number of instances written by the last upload.
+    count:           usize,
+    attr_data:       Vec<[f32; 4]>,
+    cam_dist:        Vec<f32>,
+    order:           Vec<usize>,
+}
+#[cfg(feature = "wgpu")]
+impl ParticleBuffers {
+    fn new(device: &wgpu::Device, capacity: usize, attribute_count: usize) -> Self {
+        let indirect = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Indirect Buffer"),
+                contents: bytemuck::cast_slice(&[0u32; 5]),
+                usage: wgpu::BufferUsages::INDIRECT | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+            }
+        );
+        let attributes = if attribute_count > 0 {
+            Some(device.create_buffer_init(
+                &wgpu::util::BufferInitDescriptor {
+                    label: Some("Particle Attribute Buffer"),
+                    contents: bytemuck::cast_slice(&vec![[0.0f32; 4]; attribute_count * capacity]),
+                    usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+                }
+            ))
+        }
+        else {
+            None
+        };
+
+        Self {
+            instances: create_instance_buf(device, capacity),
+            indirect,
+            attributes,
+            capacity,
+            attribute_count,
+            count:     0,
+            attr_data: Vec::new(),
+            cam_dist:  Vec::with_capacity(capacity),
+            order:     Vec::with_capacity(capacity),
+        }
+    }
+}
+
+fn sorted_lod(bands: &[LodBand]) -> Vec<LodBand> {
+    let mut bands = bands.to_vec();
+    bands.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+    bands
+}
+
+#[cfg(feature = "wgpu")]
+fn create_instance_buf(device: &wgpu::Device, max: usize) -> wgpu::Buffer {
+    device.create_buffer(
+        &wgpu::BufferDescriptor {
+            label: Some("Instance Buffer"),
+            size: max as u64 * ParticleInstance::size(),
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        }
+    )
+}
+
+/// Forces, attractors and colliders a set applies to each of its systems.
+#[derive(Clone, Default)]
+struct SharedForces {
+    attractors: Vec<ParticleAttractor>,
+    forces:     Vec<Vec3>,
+    colliders:  Vec<ParticleCollider>,
+}
+
+/// Emission settings overridden by bindings for the current frame.
+#[derive(Clone, Copy, Default)]
+struct BoundEmission {
+    rate:               Option<usize>,
+    rate_over_distance: Option<f32>,
+    inherit_velocity:   Option<f32>,
+}
+
+/// Return a system's own items followed by those shared from its set.
+fn combine<'a, T: Clone>(own: &'a [T], shared: &[T]) -> Cow<'a, [T]> {
+    if shared.is_empty() {
+        Cow::Borrowed(own)
+    }
+    else {
+        Cow::Owned(own.iter().chain(shared).cloned().collect())
+    }
+}
+
+/// Settings shared by every particle updated in a frame.
+struct StepContext<'a> {
+    delta:       f32,
+    time:        f32,
+    curve_tint:  [f32; 4],
+    curve_scale: f32,
+    world:       &'a Transform,
+    space:       SimulationSpace,
+    attractors:  &'a [ParticleAttractor],
+    forces:      &'a [Vec3],
+    colliders:   &'a [ParticleCollider],
+    anims:       &'a [ParticleAnimation],
+    /// Values of the parameters used by each animation.
+    anim_params: &'a [Vec<f32>],
+}
+
+/// Minimum number of particles updated by each thread.
+#[cfg(feature = "parallel")]
+const MIN_CHUNK_SIZE: usize = 4096;
+
+/// Return number of particles updated together, one chunk per thread of the pool.
+#[cfg(feature = "parallel")]
+fn chunk_size(count: usize) -> usize {
+    (count / rayon::current_num_threads() + 1).max(MIN_CHUNK_SIZE)
+}
+
+#[cfg(not(feature = "parallel"))]
+fn chunk_size(count: usize) -> usize {
+    count.max(1)
+}
+
+/// Update particles starting at index offset, along with their attributes,
+/// returning collision events.
+fn step_particles(
+    ctx: &StepContext,
+    offset: usize,
+    particles: &mut ParticleChunk,
+    attrs: &mut [(&Option<AttributeUpdate>, &mut [ParticleAttribute])],
+) -> Vec<ParticleEvent> {
+    let delta = ctx.delta;
+    particles.integrate(delta, ctx.attractors, ctx.forces);
+    let events = particles.collide(ctx.colliders)
+        .into_iter()
+        .map(|i| {
+            let p = to_world(ctx.space, ctx.world, &particles.get(i));
+            ParticleEvent::new(ParticleEventKind::Collision, offset + i, &p)
+        })
+        .collect();
+    for (anim, params) in ctx.anims.iter().zip(ctx.anim_params.iter()) {
+        let frame = AnimationFrame {
+            time:        ctx.time,
+            params,
+            curve_tint:  ctx.curve_tint,
+            curve_scale: ctx.curve_scale,
+        };
+        particles.animate(delta, anim, &frame);
+    }
+    for (update, values) in attrs.iter_mut() {
+        if let Some(update) = update {
+            for value in values.iter_mut() {
+                *value = update(*value, delta);
+            }
+        }
+    }
+    events
+}
+
+/// Return instances of the particles at the given indices.
+#[cfg(all(feature = "wgpu", not(feature = "parallel")))]
+fn instance_particles(
+    space: SimulationSpace,
+    world: &Transform,
+    particles: &ParticleStorage,
+    indices: &[usize],
+    material: u32,
+) -> Vec<ParticleInstance> {
+    indices.iter()
+        .map(|&i| to_world(space, world, &particles.get(i)).instance(material))
+        .collect()
+}
+
+#[cfg(all(feature = "wgpu", feature = "parallel"))]
+fn instance_particles(
+    space: SimulationSpace,
+    world: &Transform,
+    particles: &ParticleStorage,
+    indices: &[usize],
+    material: u32,
+) -> Vec<ParticleInstance> {
+    indices.par_iter()
+        .with_min_len(MIN_CHUNK_SIZE)
+        .map(|&i| to_world(space, world, &particles.get(i)).instance(material))
+        .collect()
+}
+
+/// Return particle in world space.
+fn to_world(space: SimulationSpace, transform: &Transform, particle: &Particle) -> Particle {
+    match space {
+        SimulationSpace::World => *particle,
+        SimulationSpace::Local => particle.transformed(transform),
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct ParticleAttractor {
+    pub pos: Vec3,
+    pub mass: f32,
+}
+impl ParticleAttractor {
+    pub(crate) fn new(pos: [f32; 3], mass: f32) -> Self {
+        Self { 
+            pos: pos.into(), 
+            mass 
+        }
+    }
+}
+
+/// A surface particles collide with. Restitution scales a particle's
+/// velocity after it bounces.
+#[derive(Clone, Copy)]
+pub enum ParticleCollider {
+    Plane {
+        point: Vec3,
+        normal: Vec3,
+        restitution: f32,
+    },
+    Sphere {
+        center: Vec3,
+        radius: f32,
+        restitution: f32,
+    },
+}
+impl ParticleCollider {
+    fn plane(point: [f32; 3], normal: [f32; 3], restitution: f32) -> Self {
+        Self::Plane {
+            point: point.into(),
+            normal: Vec3::from(normal).normalized(),
+            restitution,
+        }
+    }
+
+    fn sphere(center: [f32; 3], radius: f32, restitution: f32) -> Self {
+        Self::Sphere {
+            center: center.into(),
+            radius,
+            restitution,
+        }
+    }
+}
+
+/// Instances of every system in a set, sorted together by camera distance.
+#[cfg(feature = "wgpu")]
+struct MergedInstances {
+    buf:      wgpu::Buffer,
+    capacity: usize,
+    count:    u32,
+    /// Camera distance, system index and particle index.
+    keys:     Vec<(f32, usize, usize)>,
+}
+
+/// Multiplier of time passed to update, shared by every system and set it
+/// is given to, e.g. for bullet time. Clones share the same value.
+#[derive(Clone, Debug)]
+pub struct GlobalTimeScale(Arc<AtomicU32>);
+impl Default for GlobalTimeScale {
+    fn default() -> Self {
+        Self(Arc::new(AtomicU32::new(1.0f32.to_bits())))
+    }
+}
+impl GlobalTimeScale {
+    /// Set multiplier, 0 freezes every system not ignoring it.
+    pub fn set(&self, time_scale: f32) {
+        self.0.store(time_scale.max(0.0).to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+/// A group of particle systems forming one effect, moved and updated together.
+///
+/// Forces, attractors and colliders added to the set act on every system
+/// in it, including systems inserted later, on top of each system's own.
+pub struct ParticleSystemSet {
+    systems:           Vec<ParticleSystem>,
+    transform:         Option<Transform>,
+    shared:            SharedForces,
+    #[cfg(feature = "wgpu")]
+    merged:            Option<MergedInstances>,
+    time_scale:        f32,
+    global_time_scale: Option<GlobalTimeScale>,
+}
 
 impl ParticleSystemSet {
     pub fn new(systems: Vec<ParticleSystem>) -> Self {
-        Self(systems)
+        let mut set = Self {
+            systems:           Vec::with_capacity(systems.len()),
+            transform:         None,
+            shared:            SharedForces::default(),
+            #[cfg(feature = "wgpu")]
+            merged:            None,
+            time_scale:        1.0,
+            global_time_scale: None,
+        };
+        for sys in systems.into_iter() {
+            set.insert(sys);
+        }
+        set
     }
 
     pub fn systems(&self) -> &[ParticleSystem] {
-        &self.0
+        &self.systems
+    }
+
+    pub fn systems_mut(&mut self) -> &mut [ParticleSystem] {
+        &mut self.systems
+    }
+
+    /// Return first system with the given name.
+    pub fn get(&self, name: &str) -> Option<&ParticleSystem> {
+        self.systems.iter().find(|sys| sys.name == name)
+    }
+
+    /// Return first system with the given name.
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut ParticleSystem> {
+        self.systems.iter_mut().find(|sys| sys.name == name)
+    }
+
+    /// Add a system to the set. It is parented to the set's transform and
+    /// affected by the set's forces, attractors and colliders.
+    pub fn insert(&mut self, mut sys: ParticleSystem) {
+        if self.transform.is_some() {
+            sys.set_parent_transform(self.transform);
+        }
+        sys.shared = self.shared.clone();
+        #[cfg(feature = "wgpu")]
+        {
+            sys.merged = self.merged.is_some();
+        }
+        sys.set_time_scale = self.time_scale;
+        sys.global_time_scale = self.global_time_scale.clone();
+        self.systems.push(sys);
+    }
+
+    /// Remove and return first system with the given name. The returned
+    /// system no longer has the set as its parent or shares its forces.
+    pub fn remove(&mut self, name: &str) -> Option<ParticleSystem> {
+        let idx = self.systems.iter().position(|sys| sys.name == name)?;
+        let mut sys = self.systems.remove(idx);
+        if self.transform.is_some() {
+            sys.set_parent_transform(None);
+        }
+        sys.shared = SharedForces::default();
+        #[cfg(feature = "wgpu")]
+        {
+            sys.merged = false;
+        }
+        sys.set_time_scale = 1.0;
+        sys.global_time_scale = None;
+        Some(sys)
+    }
+
+    /// Set multiplier of time passed to update for every system in the set,
+    /// on top of each system's own time scale.
+    pub fn set_time_scale(&mut self, time_scale: f32) {
+        self.time_scale = time_scale.max(0.0);
+        for sys in self.systems.iter_mut() {
+            sys.set_time_scale = self.time_scale;
+        }
+    }
+
+    /// Return multiplier of time passed to update for every system in the set.
+    pub fn time_scale(&self) -> f32 {
+        self.time_scale
+    }
+
+    /// Make every system in the set, except those ignoring it, follow a
+    /// time scale shared with other systems and sets.
+    pub fn set_global_time_scale(&mut self, time_scale: Option<GlobalTimeScale>) {
+        for sys in self.systems.iter_mut() {
+            sys.global_time_scale = time_scale.clone();
+        }
+        self.global_time_scale = time_scale;
+    }
+
+    /// Return global time scale the set follows.
+    pub fn global_time_scale(&self) -> Option<&GlobalTimeScale> {
+        self.global_time_scale.as_ref()
+    }
+
+    /// Return number of systems in the set.
+    pub fn len(&self) -> usize {
+        self.systems.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.systems.is_empty()
+    }
+
+    /// Draw the set with one call from a shared instance buffer, with the
+    /// particles of every system sorted back to front together, so that
+    /// overlapping systems blend correctly. Systems no longer fill their
+    /// own buffers while merged. Every particle is drawn with the renderer's
+    /// main mesh, ignoring the LOD mesh of its system, and custom attributes
+    /// read zero.
+    ///
+    /// The shared buffer holds the max particles of the systems in the set
+    /// when this is called, so it should be called again after inserting
+    /// systems or raising their max. If it is too small the furthest
+    /// particles are left out.
+    #[cfg(feature = "wgpu")]
+    pub fn set_merged(&mut self, device: &wgpu::Device, merged: bool) {
+        for sys in self.systems.iter_mut() {
+            sys.merged = merged;
+        }
+        self.merged = if merged {
+            let capacity = self.systems.iter().map(|sys| sys.max).sum::<usize>();
+            Some(
+                MergedInstances {
+                    buf:       create_instance_buf(device, capacity.max(1)),
+                    capacity,
+                    count:     0,
+                    keys:      Vec::with_capacity(capacity),
+                }
+            )
+        }
+        else {
+            None
+        };
+    }
+
+    /// Return shared instance buffer, if the set is merged.
+    #[cfg(feature = "wgpu")]
+    pub fn merged_buf(&self) -> Option<&wgpu::Buffer> {
+        self.merged.as_ref().map(|merged| &merged.buf)
+    }
+
+    /// Return number of instances in the shared instance buffer.
+    #[cfg(feature = "wgpu")]
+    pub fn merged_count(&self) -> u32 {
+        self.merged.as_ref().map_or(0, |merged| merged.count)
+    }
+
+    #[cfg(feature = "wgpu")]
+    fn upload_merged(&mut self, queue: &wgpu::Queue) {
+        let Some(merged) = &mut self.merged else {
+            return;
+        };
+        let worlds = self.systems.iter()
+            .map(|sys| sys.world_transform())
+            .collect::<Vec<Transform>>();
+
+        merged.keys.clear();
+        for (s, sys) in self.systems.iter().enumerate().filter(|(_, sys)| sys.visible) {
+            for (i, pos) in sys.particles.position.iter().enumerate() {
+                let pos = match sys.space {
+                    SimulationSpace::World => *pos,
+                    SimulationSpace::Local => worlds[s].transform_point(*pos),
+                };
+                merged.keys.push(((pos - sys.view_pos).len(), s, i));
+            }
+        }
+        merged.keys.sort_by(|k1, k2| k2.0.total_cmp(&k1.0));
+
+        let skip = merged.keys.len().saturating_sub(merged.capacity);
+        let instances = merged.keys[skip..].iter()
+            .map(|&(_, s, i)| {
+                let sys = &self.systems[s];
+                to_world(sys.space, &worlds[s], &sys.particles.get(i)).instance(sys.material)
+            })
+            .collect::<Vec<ParticleInstance>>();
+        if !instances.is_empty() {
+            queue.write_buffer(&merged.buf, 0, bytemuck::cast_slice(&instances));
+        }
+        merged.count = instances.len() as u32;
+    }
+
+    #[cfg(not(feature = "parallel"))]
+    fn for_each_system<F: Fn(&mut ParticleSystem) + Send + Sync>(&mut self, f: F) {
+        for sys in self.systems.iter_mut() {
+            f(sys);
+        }
+    }
+
+    /// Run a function for every system in the set on the thread pool.
+    #[cfg(feature = "parallel")]
+    fn for_each_system<F: Fn(&mut ParticleSystem) + Send + Sync>(&mut self, f: F) {
+        self.systems.par_iter_mut().for_each(f);
     }
 
+    /// Simulate and upload every system in the set, see [`ParticleSystem::update`].
+    #[cfg(feature = "wgpu")]
     pub fn update(&mut self, delta: Duration, queue: &wgpu::Queue, vp: [f32; 3]) {
-        for sys in self.0.iter_mut() {
-            sys.update(delta, queue, vp);
+        self.simulate(delta, vp);
+        self.upload(queue);
+    }
+
+    /// Update systems, skipping upload and draw of those outside the frustum.
+    #[cfg(feature = "wgpu")]
+    pub fn update_culled(
+        &mut self,
+        delta: Duration,
+        queue: &wgpu::Queue,
+        vp: [f32; 3],
+        frustum: &Frustum,
+    ) {
+        self.simulate_culled(delta, vp, frustum);
+        self.upload(queue);
+    }
+
+    /// Simulate every system in the set without touching the GPU, see
+    /// [`ParticleSystem::simulate`].
+    pub fn simulate(&mut self, delta: Duration, vp: [f32; 3]) {
+        self.for_each_system(|sys| sys.simulate(delta, vp));
+    }
+
+    /// Simulate systems, marking those outside the frustum as not visible.
+    pub fn simulate_culled(&mut self, delta: Duration, vp: [f32; 3], frustum: &Frustum) {
+        self.for_each_system(|sys| sys.simulate_culled(delta, vp, frustum));
+    }
+
+    /// Upload every visible system, or the shared instance buffer if the
+    /// set is merged.
+    #[cfg(feature = "wgpu")]
+    pub fn upload(&mut self, queue: &wgpu::Queue) {
+        self.for_each_system(|sys| sys.upload(queue));
+        self.upload_merged(queue);
+    }
+
+    /// Set what every system in the set does while outside the frustum.
+    pub fn set_offscreen_mode(&mut self, mode: OffscreenMode) {
+        for sys in self.systems.iter_mut() {
+            sys.set_offscreen_mode(mode);
         }
     }
 
+    pub fn add_force(&mut self, force: [f32; 3]) {
+        self.shared.forces.push(force.into());
+        self.share();
+    }
+
     pub fn add_attractor(&mut self, pos: [f32; 3], mass: f32) {
-        for sys in self.0.iter_mut() {
-            sys.attractors.push(ParticleAttractor::new(pos, mass));
+        self.shared.attractors.push(ParticleAttractor::new(pos, mass));
+        self.share();
+    }
+
+    /// Add an infinite plane that particles of every system bounce off of.
+    pub fn add_plane_collider(&mut self, point: [f32; 3], normal: [f32; 3], restitution: f32) {
+        self.shared.colliders.push(ParticleCollider::plane(point, normal, restitution));
+        self.share();
+    }
+
+    /// Add a sphere that particles of every system bounce off of.
+    pub fn add_sphere_collider(&mut self, center: [f32; 3], radius: f32, restitution: f32) {
+        self.shared.colliders.push(ParticleCollider::sphere(center, radius, restitution));
+        self.share();
+    }
+
+    fn share(&mut self) {
+        for sys in self.systems.iter_mut() {
+            sys.shared = self.shared.clone();
+        }
+    }
+
+    /// Set root transform of the set. Each system's own transform is
+    /// treated as an offset from the root.
+    pub fn set_transform(&mut self, transform: Transform) {
+        self.transform = Some(transform);
+        for sys in self.systems.iter_mut() {
+            sys.set_parent_transform(Some(transform));
+        }
+    }
+
+    /// Return root transform of the set.
+    pub fn transform(&self) -> Transform {
+        self.transform.unwrap_or_default()
+    }
+
+    /// Move every system in the set together, keeping their offsets.
+    pub fn set_position(&mut self, position: [f32; 3]) {
+        let mut transform = self.transform();
+        transform.translation = position.into();
+        self.set_transform(transform);
+    }
+
+    /// Set value of a named parameter on every system in the set.
+    pub fn set_parameter<P: Into<ParticleParameter>>(&mut self, name: &str, value: P) {
+        let value = value.into();
+        for sys in self.systems.iter_mut() {
+            sys.set_parameter(name, value);
+        }
+    }
+
+    pub fn play(&mut self) {
+        for sys in self.systems.iter_mut() {
+            sys.play();
+        }
+    }
+
+    pub fn pause(&mut self) {
+        for sys in self.systems.iter_mut() {
+            sys.pause();
+        }
+    }
+
+    pub fn stop(&mut self) {
+        for sys in self.systems.iter_mut() {
+            sys.stop();
+        }
+    }
+
+    pub fn restart(&mut self) {
+        for sys in self.systems.iter_mut() {
+            sys.restart();
+        }
+    }
+}
+
+#[test]
+fn aabb_from_points() {
+    let aabb = Aabb::from_points([[1.0, -2.0, 0.5], [-1.0, 3.0, 0.0], [0.0, 0.0, 2.0]]).unwrap();
+    assert!(aabb.min == [-1.0, -2.0, 0.0]);
+    assert!(aabb.max == [1.0, 3.0, 2.0]);
+    assert!(Aabb::from_points([]).is_none());
+}
+
+#[test]
+fn attribute_padding() {
+    assert!(ParticleAttribute::F32(1.0).to_array() == [1.0, 0.0, 0.0, 0.0]);
+    assert!(ParticleAttribute::Vec3([1.0, 2.0, 3.0]).to_array() == [1.0, 2.0, 3.0, 0.0]);
+}
+
+#[test]
+fn attribute_channels() {
+    let mut sys = ParticleSystem::headless(&ParticleSystemDescriptor::default()).unwrap();
+    sys.insert_attribute(
+        ParticleAttributeDescriptor {
+            name:   "age",
+            init:   Box::new(|_| ParticleAttribute::F32(0.0)),
+            update: Some(Box::new(|value, delta| ParticleAttribute::F32(value.to_array()[0] + delta))),
+            upload: true,
         }
+    );
+    let delta = Duration::from_millis(16);
+    sys.simulate(delta, [0.0; 3]);
+    sys.simulate(delta, [0.0; 3]);
+    let age = |sys: &ParticleSystem, i| sys.attribute("age", i).unwrap().to_array()[0];
+    assert!((age(&sys, 0) - 0.032).abs() < 1e-6);
+    assert!((age(&sys, 1) - 0.016).abs() < 1e-6);
+
+    sys.set_attribute("age", 1, ParticleAttribute::F32(5.0));
+    assert!(age(&sys, 1) == 5.0);
+
+    // Living particles get the initial value of attributes added later.
+    sys.insert_attribute(
+        ParticleAttributeDescriptor {
+            name:   "tint",
+            init:   Box::new(|_| ParticleAttribute::Vec3([1.0, 0.5, 0.0])),
+            update: None,
+            upload: false,
+        }
+    );
+    assert!(sys.attribute("tint", 1) == Some(ParticleAttribute::Vec3([1.0, 0.5, 0.0])));
+    assert!(sys.attribute("tint", 2).is_none());
+    assert!(sys.uploaded_attribute_count() == 1);
+}
+
+#[cfg(feature = "wgpu")]
+#[test]
+#[ignore = "requires a GPU adapter"]
+fn sort_modes() {
+    use crate::gpu_particle_system::{gpu_device, read_buffer};
+
+    let (device, queue) = gpu_device();
+    let uploaded_x = |sort| {
+        let desc = ParticleSystemDescriptor {
+            max:    3,
+            sort,
+            bounds: ParticleSystemBounds {
+                area:     [(0.0, 5.0), (0.0, 0.0), (0.0, 0.0)],
+                velocity: [(0.0, 0.0); 3],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut sys = ParticleSystem::new(&device, &desc).unwrap();
+        for _ in 0..3 {
+            sys.update(Duration::from_millis(16), &queue, [100.0, 0.0, 0.0]);
+        }
+        let data = read_buffer(&device, &queue, sys.particle_buf().unwrap());
+        let uploaded = bytemuck::cast_slice::<u8, [f32; 10]>(&data).iter()
+            .map(|instance| instance[0])
+            .collect::<Vec<f32>>();
+        let stored = sys.particles().map(|p| p.position[0]).collect::<Vec<f32>>();
+        (uploaded, stored)
+    };
+
+    // Particles are stored oldest first.
+    let (uploaded, stored) = uploaded_x(SortMode::None);
+    assert!(uploaded == stored);
+    let (uploaded, stored) = uploaded_x(SortMode::ByAge);
+    assert!(uploaded == stored);
+
+    // The view is at large x, so the furthest particles have the lowest x.
+    let (uploaded, mut stored) = uploaded_x(SortMode::BackToFront);
+    stored.sort_by(f32::total_cmp);
+    assert!(uploaded == stored);
+    let (uploaded, mut stored) = uploaded_x(SortMode::FrontToBack);
+    stored.sort_by(|a, b| b.total_cmp(a));
+    assert!(uploaded == stored);
+}
+
+#[cfg(feature = "wgpu")]
+#[test]
+#[ignore = "requires a GPU adapter"]
+fn attribute_upload() {
+    use crate::gpu_particle_system::{gpu_device, read_buffer};
+
+    let (device, queue) = gpu_device();
+    let desc = ParticleSystemDescriptor { max: 4, rate: 2, sort: SortMode::None, ..Default::default() };
+    let mut sys = ParticleSystem::new(&device, &desc).unwrap();
+    let attribute = |name, value, upload| ParticleAttributeDescriptor {
+        name,
+        init:   Box::new(move |_| value),
+        update: None,
+        upload,
+    };
+    sys.add_attribute(&device, attribute("color", ParticleAttribute::Vec4([1.0, 2.0, 3.0, 4.0]), true));
+    sys.add_attribute(&device, attribute("hidden", ParticleAttribute::F32(9.0), false));
+    sys.add_attribute(&device, attribute("size", ParticleAttribute::F32(5.0), true));
+    sys.update(Duration::from_millis(16), &queue, [0.0; 3]);
+
+    let data = read_buffer(&device, &queue, sys.attribute_buf().unwrap());
+    let values = bytemuck::cast_slice::<u8, [f32; 4]>(&data);
+    assert!(values.len() == 8);
+    for i in 0..2 {
+        assert!(values[i * 2] == [1.0, 2.0, 3.0, 4.0]);
+        assert!(values[i * 2 + 1] == [5.0, 0.0, 0.0, 0.0]);
+    }
+
+    // A buffer that wasn't resized for new attributes isn't drawn.
+    sys.insert_attribute(attribute("extra", ParticleAttribute::F32(1.0), true));
+    assert!(sys.attribute_buf().is_none());
+    sys.update(Duration::from_millis(16), &queue, [0.0; 3]);
+    sys.resize_buffers(&device);
+    assert!(sys.attribute_buf().unwrap().size() == 4 * 3 * 16);
+}
+
+#[test]
+fn playback_control() {
+    let desc = ParticleSystemDescriptor {
+        rate: 2,
+        life: 0.05,
+        bounds: ParticleSystemBounds { life: (10.0, 0.0), ..Default::default() },
+        ..Default::default()
+    };
+    let delta = Duration::from_millis(16);
+
+    // Non-looping systems stop emitting once their life has elapsed.
+    let mut sys = ParticleSystem::headless(&desc).unwrap();
+    for _ in 0..5 {
+        sys.simulate(delta, [0.0; 3]);
+    }
+    assert!(sys.playback_state() == PlaybackState::Stopped);
+    assert!(sys.alive_count() == 8);
+
+    // Playing a stopped system restarts emission, keeping living particles.
+    sys.play();
+    sys.simulate(delta, [0.0; 3]);
+    assert!(sys.alive_count() == 10);
+    sys.restart();
+    assert!(sys.alive_count() == 0 && sys.playback_state() == PlaybackState::Playing);
+
+    sys.simulate(delta, [0.0; 3]);
+    sys.pause();
+    let paused = sys.snapshot();
+    sys.simulate(delta, [0.0; 3]);
+    assert!(sys.snapshot() == paused);
+    sys.play();
+    sys.simulate(delta, [0.0; 3]);
+    assert!(sys.alive_count() == 4);
+
+    sys.stop();
+    sys.simulate(delta, [0.0; 3]);
+    assert!(sys.alive_count() == 4);
+
+    // Looping systems keep emitting after their life has elapsed.
+    let mut sys = ParticleSystem::headless(&ParticleSystemDescriptor { life: 0.02, looping: true, ..desc }).unwrap();
+    for _ in 0..3 {
+        sys.simulate(delta, [0.0; 3]);
+    }
+    assert!(sys.playback_state() == PlaybackState::Playing);
+    assert!(sys.alive_count() == 6);
+}
+
+#[test]
+fn prewarm_steps() {
+    let desc = ParticleSystemDescriptor {
+        rate: 1,
+        bounds: ParticleSystemBounds { life: (10.0, 0.0), ..Default::default() },
+        ..Default::default()
+    };
+    let mut sys = ParticleSystem::headless(&desc).unwrap();
+    sys.prewarm(0.5);
+    assert!(sys.alive_count() == 30);
+    assert!(sys.events().is_empty());
+
+    // Prewarming stops with the system.
+    let mut sys = ParticleSystem::headless(&ParticleSystemDescriptor { life: 0.25, ..desc }).unwrap();
+    sys.prewarm(1.0);
+    assert!(sys.playback_state() == PlaybackState::Stopped);
+    assert!(sys.alive_count() == 16);
+}
+
+#[test]
+fn spawn_interpolation() {
+    let desc = ParticleSystemDescriptor {
+        rate: 0,
+        rate_over_distance: 1.0,
+        bounds: ParticleSystemBounds {
+            velocity: [(0.0, 0.0); 3],
+            life:     (10.0, 0.0),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let delta = Duration::from_millis(16);
+    let mut sys = ParticleSystem::headless(&desc).unwrap();
+    sys.simulate(delta, [0.0; 3]);
+    assert!(sys.alive_count() == 0);
+
+    // One particle per unit moved, spread along the path.
+    sys.set_position([4.0, 0.0, 0.0]);
+    sys.simulate(delta, [0.0; 3]);
+    let xs = sys.particles().map(|p| p.position[0]).collect::<Vec<f32>>();
+    assert!(xs == vec![1.0, 2.0, 3.0, 4.0]);
+
+    // Movement while paused doesn't leave a trail.
+    sys.pause();
+    sys.set_position([104.0, 0.0, 0.0]);
+    sys.simulate(delta, [0.0; 3]);
+    sys.play();
+    sys.simulate(delta, [0.0; 3]);
+    assert!(sys.alive_count() == 4);
+
+    // Nor does movement while culled with OffscreenMode::Pause.
+    let mut planes = [[0.0, 0.0, 0.0, 1.0]; 6];
+    planes[0] = [-1.0, 0.0, 0.0, -500.0];
+    let frustum = Frustum::from_planes(planes);
+    sys.set_offscreen_mode(OffscreenMode::Pause);
+    sys.simulate_culled(delta, [0.0; 3], &frustum);
+    assert!(!sys.is_visible());
+    sys.set_position([0.0, 0.0, 0.0]);
+    sys.simulate_culled(delta, [0.0; 3], &frustum);
+    assert!(sys.alive_count() == 4);
+
+    // New particles inherit the emitter velocity.
+    let desc = ParticleSystemDescriptor { rate: 1, rate_over_distance: 0.0, inherit_velocity: 1.0, ..desc };
+    let mut sys = ParticleSystem::headless(&desc).unwrap();
+    sys.set_position([1.6, 0.0, 0.0]);
+    sys.simulate(delta, [0.0; 3]);
+    assert!(sys.particles().all(|p| (p.velocity[0] - 100.0).abs() < 0.01));
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn parallel_matches_sequential() {
+    // A single thread pool runs every chunk and system in turn, while the
+    // larger pool splits particles into several chunks.
+    let run = |threads| {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+        pool.install(|| {
+            let desc = ParticleSystemDescriptor { max: 20000, rate: 5000, ..Default::default() };
+            let mut set = ParticleSystemSet::new(vec![
+                ParticleSystem::headless(&desc).unwrap(),
+                ParticleSystem::headless(&ParticleSystemDescriptor { name: "second", ..desc }).unwrap(),
+            ]);
+            set.add_force([0.0, -9.8, 0.0]);
+            set.add_attractor([0.0, 1.0, 0.0], 1e9);
+            set.add_plane_collider([0.0, -0.01, 0.0], [0.0, 1.0, 0.0], 0.5);
+            for _ in 0..3 {
+                set.simulate(Duration::from_millis(16), [0.0; 3]);
+            }
+            set.systems().iter()
+                .map(|sys| (sys.snapshot(), sys.events().len()))
+                .collect::<Vec<_>>()
+        })
+    };
+    let sequential = run(1);
+    assert!(sequential[0].0.particles.len() == 15000);
+    assert!(sequential == run(4));
+}
+
+#[test]
+fn set_insert_and_remove() {
+    let smoke = ParticleSystemDescriptor { name: "smoke", ..Default::default() };
+    let fire = ParticleSystemDescriptor { name: "fire", ..Default::default() };
+
+    let mut set = ParticleSystemSet::new(vec![ParticleSystem::headless(&smoke).unwrap()]);
+    set.add_force([0.0, -9.8, 0.0]);
+    set.set_position([1.0, 2.0, 3.0]);
+    set.insert(ParticleSystem::headless(&fire).unwrap());
+
+    let fire = set.get("fire").unwrap();
+    assert!(fire.shared.forces.len() == 1);
+    assert!(fire.world_transform().translation == Vec3::new(1.0, 2.0, 3.0));
+
+    let smoke = set.remove("smoke").unwrap();
+    assert!(smoke.shared.forces.is_empty());
+    assert!(smoke.parent_transform().is_none());
+    assert!(set.len() == 1 && set.get("smoke").is_none());
+}
+
+#[cfg(feature = "wgpu")]
+#[test]
+#[ignore = "requires a GPU adapter"]
+fn merged_set_count() {
+    let (device, queue) = crate::gpu_particle_system::gpu_device();
+    let near = ParticleSystemDescriptor { max: 8, rate: 2, ..Default::default() };
+    let far = ParticleSystemDescriptor { max: 8, rate: 3, pos: Vec3::new(0.0, 0.0, -10.0), ..Default::default() };
+
+    let mut set = ParticleSystemSet::new(vec![
+        ParticleSystem::new(&device, &near).unwrap(),
+        ParticleSystem::new(&device, &far).unwrap(),
+    ]);
+    set.set_merged(&device, true);
+    set.update(Duration::from_millis(16), &queue, [0.0, 0.0, 5.0]);
+
+    assert!(set.merged_count() == 5);
+    // Systems no longer upload their own instances while merged.
+    assert!(set.systems().iter().all(|sys| sys.merged));
+}
+
+#[test]
+fn time_scale_emission() {
+    let desc = ParticleSystemDescriptor { rate: 4, ..Default::default() };
+    let mut set = ParticleSystemSet::new(vec![ParticleSystem::headless(&desc).unwrap()]);
+    let global = GlobalTimeScale::default();
+    set.set_global_time_scale(Some(global.clone()));
+    set.systems_mut()[0].set_time_scale(0.5);
+    set.systems_mut()[0].set_ignore_global_time_scale(true);
+    set.set_time_scale(0.5);
+
+    // One particle per frame at a quarter speed.
+    for _ in 0..3 {
+        set.simulate(Duration::from_millis(16), [0.0; 3]);
+    }
+    assert!(set.systems()[0].alive_count() == 3);
+
+    // Only systems that don't ignore the global time scale are frozen,
+    // whether they are in a set or not.
+    set.insert(ParticleSystem::headless(&desc).unwrap());
+    let mut standalone = ParticleSystem::headless(&desc).unwrap();
+    standalone.set_global_time_scale(Some(global.clone()));
+    global.set(0.0);
+    set.simulate(Duration::from_millis(16), [0.0; 3]);
+    standalone.simulate(Duration::from_millis(16), [0.0; 3]);
+    assert!(set.systems()[0].alive_count() == 4);
+    assert!(set.systems()[1].alive_count() == 0);
+    assert!(standalone.alive_count() == 0);
+
+    set.set_time_scale(0.0);
+    set.simulate(Duration::from_millis(16), [0.0; 3]);
+    assert!(set.systems()[0].alive_count() == 4);
+
+    // Nor does a frozen emitter leave a trail.
+    let desc = ParticleSystemDescriptor { rate: 0, rate_over_distance: 10.0, ..Default::default() };
+    let mut sys = ParticleSystem::headless(&desc).unwrap();
+    sys.set_time_scale(0.0);
+    sys.set_position([1.0, 0.0, 0.0]);
+    sys.simulate(Duration::from_millis(16), [0.0; 3]);
+    assert!(sys.alive_count() == 0);
+}
+
+#[test]
+fn reject_invalid_descriptor() {
+    let invalid = ParticleSystemDescriptor { max: 2, rate: 3, ..Default::default() };
+    let err = ParticleSystem::headless(&invalid).err();
+    assert!(matches!(err, Some(crate::error::BrumousError::RateExceedsMax(_, 3, 2))));
+
+    let mut sys = ParticleSystem::headless(&ParticleSystemDescriptor::default()).unwrap();
+    assert!(sys.apply_descriptor(&invalid).is_err());
+    assert!(sys.rate() == ParticleSystemDescriptor::default().rate);
+
+    #[cfg(feature = "wgpu")]
+    if let Some((device, _)) = crate::gpu_particle_system::test_device() {
+        assert!(ParticleSystem::new(&device, &invalid).is_err());
+    }
+}
+
+#[test]
+fn lod_bands() {
+    let bands = [
+        LodBand { distance: 20.0, max: 0.5, ..Default::default() },
+        LodBand { distance: 10.0, rate: 0.5, interval: 2, mesh: Some(0), ..Default::default() },
+        LodBand::default(),
+    ];
+    let desc = ParticleSystemDescriptor { max: 100, rate: 100, lod: &bands, ..Default::default() };
+    let delta = Duration::from_millis(16);
+
+    let mut near = ParticleSystem::headless(&desc).unwrap();
+    near.simulate(delta, [0.0; 3]);
+    assert!(near.lod_level() == Some(0) && near.lod_mesh().is_none());
+    assert!(near.alive_count() == 100);
+
+    // Half the rate, simulated every other frame.
+    let mut far = ParticleSystem::headless(&desc).unwrap();
+    far.simulate(delta, [15.0, 0.0, 0.0]);
+    assert!(far.lod_level() == Some(1) && far.lod_mesh() == Some(0));
+    assert!(far.alive_count() == 0);
+    far.simulate(delta, [15.0, 0.0, 0.0]);
+    assert!(far.alive_count() == 50);
+
+    let mut capped = ParticleSystem::headless(&desc).unwrap();
+    capped.simulate(delta, [25.0, 0.0, 0.0]);
+    assert!(capped.lod_level() == Some(2));
+    assert!(capped.alive_count() == 50);
+
+    // A NaN distance sorts last and is never selected.
+    near.set_lod(&[LodBand { distance: f32::NAN, ..Default::default() }, LodBand::default()]);
+    near.simulate(delta, [0.0; 3]);
+    assert!(near.lod_level() == Some(0));
+}
+
+#[test]
+fn snapshot_restore() {
+    let desc = ParticleSystemDescriptor { rate: 3, ..Default::default() };
+    let create = || {
+        let mut sys = ParticleSystem::headless(&desc).unwrap();
+        sys.add_spawn_expr(ParticleChannel::Mass, Expr::new("1 + time").unwrap());
+        sys
+    };
+    let mut sys = create();
+    sys.add_force([0.0, -9.8, 0.0]);
+    let delta = Duration::from_millis(16);
+    for _ in 0..5 {
+        sys.simulate(delta, [0.0; 3]);
+    }
+    let snapshot = sys.snapshot();
+    for _ in 0..5 {
+        sys.simulate(delta, [0.0; 3]);
+    }
+    let expected = sys.snapshot();
+
+    let mut restored = create();
+    restored.restore(&snapshot);
+    assert!(restored.snapshot() == snapshot);
+    for _ in 0..5 {
+        restored.simulate(delta, [0.0; 3]);
+    }
+    assert!(restored.snapshot() == expected);
+}
+
+#[test]
+fn expression_channels() {
+    let desc = ParticleSystemDescriptor { rate: 2, ..Default::default() };
+    let mut sys = ParticleSystem::headless(&desc).unwrap();
+    sys.set_parameter("intensity", 3.0);
+    sys.add_spawn_expr(ParticleChannel::Life, Expr::new("10 + random").unwrap());
+    sys.add_spawn_expr(ParticleChannel::Mass, Expr::new("intensity").unwrap());
+    sys.add_animation(ParticleAnimation::Expr(ParticleChannel::Scale, Expr::new("intensity * 2").unwrap()));
+    sys.add_animation(ParticleAnimation::Expr(ParticleChannel::Red, Expr::new("random").unwrap()));
+
+    sys.simulate(Duration::from_millis(16), [0.0; 3]);
+    assert!(sys.alive_count() == 2);
+    for p in sys.particles() {
+        assert!(p.life > 9.9 && p.life < 11.0);
+        assert!(p.scale == 6.0);
     }
+
+    // Animations see the same random value every frame, different per particle.
+    let red = sys.particles().map(|p| p.color[0]).collect::<Vec<f32>>();
+    assert!(red[0] != red[1]);
+    sys.simulate(Duration::from_millis(16), [0.0; 3]);
+    assert!(sys.particles().take(2).map(|p| p.color[0]).eq(red.into_iter()));
+}
+
+#[test]
+fn parameter_bindings() {
+    let mut sys = ParticleSystem::headless(&ParticleSystemDescriptor::default()).unwrap();
+    sys.set_parameter("intensity", 2.0);
+    sys.set_parameter("team_color", [0.0, 0.5, 1.0, 1.0]);
+    sys.declare_parameter("intensity", 5.0);
+    sys.add_binding(ParticleBinding::Rate, Expr::new("intensity * 3").unwrap());
+    sys.add_binding(ParticleBinding::Spawn(ParticleChannel::Scale), Expr::new("team_color.g").unwrap());
+    assert!(sys.parameter("intensity") == Some(ParticleParameter::Float(2.0)));
+
+    sys.simulate(Duration::from_millis(16), [0.0; 3]);
+    assert!(sys.alive_count() == 6);
+    assert!(sys.particles().all(|p| (p.scale - 0.5).abs() <= 0.002));
+
+    sys.set_parameter("intensity", 0.0);
+    sys.simulate(Duration::from_millis(16), [0.0; 3]);
+    assert!(sys.alive_count() == 6);
+
+    // Rate and spawn bounds go back to those of the descriptor.
+    sys.clear_bindings();
+    sys.simulate(Duration::from_millis(16), [0.0; 3]);
+    let base = ParticleSystemDescriptor::default().bounds.scale;
+    assert!(sys.alive_count() == 7);
+    assert!(sys.particles().skip(6).all(|p| (p.scale - base.0).abs() <= base.1));
+
+    // Spawned life and mass stay valid whatever the parameters.
+    sys.add_binding(ParticleBinding::Spawn(ParticleChannel::Life), Expr::new("-intensity").unwrap());
+    sys.add_binding(ParticleBinding::Spawn(ParticleChannel::Mass), Expr::new("-intensity").unwrap());
+    sys.set_parameter("intensity", 5.0);
+    sys.simulate(Duration::from_millis(16), [0.0; 3]);
+    assert!(sys.alive_count() == 8);
+    assert!(sys.snapshot().particles[7..].iter().all(|p| p.life >= 0.0 && p.mass > 0.0));
+    sys.clear_bindings();
+
+    sys.add_binding(ParticleBinding::Rate, Expr::new("intensity").unwrap());
+    sys.set_parameter("intensity", 1e9);
+    sys.simulate(Duration::from_millis(16), [0.0; 3]);
+    assert!(sys.rate() == ParticleSystemDescriptor::default().max);
 }
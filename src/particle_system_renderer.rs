@@ -1,6 +1,9 @@
+use std::fs;
+
 use wgpu::util::DeviceExt;
 
 use crate::error::{BrumousError, BrumousResult};
+use crate::reload::FileWatcher;
 use crate::texture::Texture;
 use crate::particle::{
     ParticleVertex, 
@@ -16,14 +19,28 @@ use crate::DepthTextureDescriptor;
 
 const SHADER: &str = include_str!("particle.wgsl");
 
+/// Instances the zeroed attribute buffer holds, see
+/// [`ParticleSystemRenderer::attribute_fallback`].
+pub const FALLBACK_INSTANCES: u32 = 1024;
+
 
 pub struct ParticleSystemRenderer {
     pub pipeline:    wgpu::RenderPipeline,
     pub bind_groups: Vec<wgpu::BindGroup>,
     pub mesh:        ParticleMesh,
+    pub lod_meshes:  Vec<ParticleLodMesh>,
     pub view_data:   wgpu::Buffer,
     pub lights:      wgpu::Buffer,
     pub max_lights:  u64,
+    pub attribute_count: u32,
+    /// Zeroed attributes bound when drawing instances without attributes of
+    /// their own, such as merged sets and GPU systems. None if the renderer
+    /// reads no attributes.
+    pub attribute_fallback: Option<wgpu::Buffer>,
+    source:          RendererSource,
+    watcher:         FileWatcher,
+    light_data:      Vec<Light>,
+    view:            ViewData,
 }
 impl ParticleSystemRenderer {
     pub fn new(
@@ -31,6 +48,15 @@ impl ParticleSystemRenderer {
         queue: &wgpu::Queue,
         config: &wgpu::SurfaceConfiguration,
         desc: &ParticleSystemRendererDescriptor,
+    ) -> BrumousResult<Self> {
+        Self::build(device, queue, config.format, desc)
+    }
+
+    fn build(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        format: wgpu::TextureFormat,
+        desc: &ParticleSystemRendererDescriptor,
     ) -> BrumousResult<Self> {
         let texture = Texture::new(device, queue, desc.texture)?;
         let fs_entry = if desc.texture.is_some() {
@@ -167,13 +193,38 @@ impl ParticleSystemRenderer {
             )
         ];
 
+        let source = match desc.shader {
+            Some(path) => fs::read_to_string(path)?,
+            None => SHADER.to_string(),
+        };
         let shader = device.create_shader_module(
             wgpu::ShaderModuleDescriptor {
                 label: Some("Shader"),
-                source: wgpu::ShaderSource::Wgsl(SHADER.into()),
+                source: wgpu::ShaderSource::Wgsl(source.into()),
             }
         );
 
+        let attributes = (0..desc.attributes)
+            .map(|i| wgpu::VertexAttribute {
+                format: wgpu::VertexFormat::Float32x4,
+                offset: i as u64 * 16,
+                shader_location: 13 + i,
+            })
+            .collect::<Vec<wgpu::VertexAttribute>>();
+        let mut buffers = vec![
+            ParticleVertex::layout(),
+            ParticleInstance::layout(),
+        ];
+        if desc.attributes > 0 {
+            buffers.push(
+                wgpu::VertexBufferLayout {
+                    array_stride: desc.attributes as u64 * 16,
+                    step_mode: wgpu::VertexStepMode::Instance,
+                    attributes: &attributes,
+                }
+            );
+        }
+
         let pipeline_layout = device.create_pipeline_layout(
             &wgpu::PipelineLayoutDescriptor {
                 label: Some("Render Pipeline Layout"),
@@ -182,67 +233,157 @@ impl ParticleSystemRenderer {
             }
         );
 
-        let pipeline = device.create_render_pipeline(
-            &wgpu::RenderPipelineDescriptor {
-                label: Some("Render Pipeline"),
-                layout: Some(&pipeline_layout),
-                vertex: wgpu::VertexState {
-                    module: &shader,
-                    entry_point: "vs_main",
-                    buffers: &[
-                        ParticleVertex::layout(),
-                        ParticleInstance::layout(),
-                    ],
-                },
-                fragment: Some(wgpu::FragmentState {
-                    module: &shader,
-                    entry_point: fs_entry,
-                    targets: &[
-                        Some(wgpu::ColorTargetState {
-                            format: config.format,
-                            blend: Some(wgpu::BlendState::REPLACE),
-                            write_mask: wgpu::ColorWrites::ALL,
-                        }),
-                    ],
-                }),
-                primitive: wgpu::PrimitiveState {
-                    topology: wgpu::PrimitiveTopology::from(&desc.mesh_type),
-                    strip_index_format: None,
-                    front_face: wgpu::FrontFace::Ccw,
-                    cull_mode: Some(wgpu::Face::Back),
-                    polygon_mode: wgpu::PolygonMode::Fill,
-                    unclipped_depth: false,
-                    conservative: false,
-                },
-                depth_stencil: from_depth_texture_desc(&desc.depth_texture),
-                // Some(wgpu::DepthStencilState {
-                //     format: Texture::DEPTH_FORMAT,
-                //     depth_write_enabled: true,
-                //     depth_compare: wgpu::CompareFunction::Less,
-                //     stencil: wgpu::StencilState::default(),
-                //     bias: wgpu::DepthBiasState::default(),
-                // }),
-                multisample: wgpu::MultisampleState {
-                    count: 1,
-                    mask: !0,
-                    alpha_to_coverage_enabled: false,
-                },
-                multiview: None,
-            }
-        );
+        let create_pipeline = |topology: wgpu::PrimitiveTopology| {
+            device.create_render_pipeline(
+                &wgpu::RenderPipelineDescriptor {
+                    label: Some("Render Pipeline"),
+                    layout: Some(&pipeline_layout),
+                    vertex: wgpu::VertexState {
+                        module: &shader,
+                        entry_point: "vs_main",
+                        buffers: &buffers,
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        module: &shader,
+                        entry_point: fs_entry,
+                        targets: &[
+                            Some(wgpu::ColorTargetState {
+                                format,
+                                blend: Some(wgpu::BlendState::REPLACE),
+                                write_mask: wgpu::ColorWrites::ALL,
+                            }),
+                        ],
+                    }),
+                    primitive: wgpu::PrimitiveState {
+                        topology,
+                        strip_index_format: None,
+                        front_face: wgpu::FrontFace::Ccw,
+                        cull_mode: Some(wgpu::Face::Back),
+                        polygon_mode: wgpu::PolygonMode::Fill,
+                        unclipped_depth: false,
+                        conservative: false,
+                    },
+                    depth_stencil: from_depth_texture_desc(&desc.depth_texture),
+                    // Some(wgpu::DepthStencilState {
+                    //     format: Texture::DEPTH_FORMAT,
+                    //     depth_write_enabled: true,
+                    //     depth_compare: wgpu::CompareFunction::Less,
+                    //     stencil: wgpu::StencilState::default(),
+                    //     bias: wgpu::DepthBiasState::default(),
+                    // }),
+                    multisample: wgpu::MultisampleState {
+                        count: 1,
+                        mask: !0,
+                        alpha_to_coverage_enabled: false,
+                    },
+                    multiview: None,
+                }
+            )
+        };
+        let pipeline = create_pipeline(wgpu::PrimitiveTopology::from(&desc.mesh_type));
+
+        let mut lod_meshes = Vec::with_capacity(desc.lod_meshes.len());
+        for mesh_type in desc.lod_meshes.iter() {
+            lod_meshes.push(
+                ParticleLodMesh {
+                    mesh:     ParticleMesh::new(device, mesh_type)?,
+                    pipeline: create_pipeline(wgpu::PrimitiveTopology::from(mesh_type)),
+                }
+            );
+        }
+
+        let attribute_fallback = (desc.attributes > 0).then(|| {
+            device.create_buffer_init(
+                &wgpu::util::BufferInitDescriptor {
+                    label: Some("Attribute Fallback Buffer"),
+                    contents: bytemuck::cast_slice(
+                        &vec![[0.0f32; 4]; (desc.attributes * FALLBACK_INSTANCES) as usize]
+                    ),
+                    usage: wgpu::BufferUsages::VERTEX,
+                }
+            )
+        });
+
+        let source = RendererSource::new(format, desc);
+        let mut watcher = FileWatcher::new();
+        for path in source.files() {
+            watcher.watch(path);
+        }
 
         Ok(
             Self {
                 pipeline,
                 bind_groups,
                 mesh,
+                lod_meshes,
                 view_data,
                 lights,
                 max_lights: desc.max_lights as u64,
+                attribute_count: desc.attributes,
+                attribute_fallback,
+                source,
+                watcher,
+                light_data: vec![Light::default(); desc.max_lights],
+                view: ViewData::default(),
             }
         )
     }
 
+    /// Rebuild the renderer if its texture, custom meshes or shader changed
+    /// on disk, returning whether it was rebuilt. Should be called
+    /// periodically, e.g. once a second, while tuning effects.
+    ///
+    /// If rebuilding fails the renderer is left unchanged, and is rebuilt
+    /// on the next poll.
+    pub fn poll_changes(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) -> BrumousResult<bool> {
+        let changed = self.watcher.poll_changes();
+        if changed.is_empty() {
+            return Ok(false);
+        }
+        let source = self.source.clone();
+        let lod_meshes = source.lod_meshes.iter()
+            .map(|mesh| mesh.mesh_type())
+            .collect::<Vec<ParticleMeshType>>();
+        if let Err(err) = self.reload(device, queue, &source.descriptor(&lod_meshes)) {
+            // A file caught mid-save is read again on the next poll.
+            for path in changed {
+                self.watcher.invalidate(path);
+            }
+            return Err(err);
+        }
+        Ok(true)
+    }
+
+    /// Rebuild the renderer from a new descriptor, keeping its surface
+    /// format, view data and lights.
+    pub fn reload(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        desc: &ParticleSystemRendererDescriptor,
+    ) -> BrumousResult<()> {
+        *self = self.rebuilt(device, queue, desc)?;
+        Ok(())
+    }
+
+    /// Build a renderer from a new descriptor with the surface format, view
+    /// data and lights of this one, leaving this one unchanged.
+    pub(crate) fn rebuilt(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        desc: &ParticleSystemRendererDescriptor,
+    ) -> BrumousResult<Self> {
+        let mut rend = Self::build(device, queue, self.source.format, desc)?;
+        rend.view = self.view;
+        let count = rend.light_data.len().min(self.light_data.len());
+        rend.light_data[..count].copy_from_slice(&self.light_data[..count]);
+
+        queue.write_buffer(&rend.view_data, 0, bytemuck::cast_slice(&[rend.view]));
+        queue.write_buffer(&rend.lights, 0, bytemuck::cast_slice(&rend.light_data));
+        Ok(rend)
+    }
+
     pub fn add_light(
         &mut self, 
         queue: &wgpu::Queue, 
@@ -255,24 +396,120 @@ impl ParticleSystemRenderer {
                 BrumousError::InvalidLightIndex(idx, self.max_lights)
             );
         }
+        let light = Light::new(position, color);
+        self.light_data[idx as usize] = light;
         queue.write_buffer(
             &self.lights, 
             idx * Light::size(), 
-            bytemuck::cast_slice(&[Light::new(position, color)])
+            bytemuck::cast_slice(&[light])
         );
         Ok(())
     }
 
     pub fn set_view_proj(&mut self, queue: &wgpu::Queue, vp: [[f32; 4]; 4]) {
+        self.view.view_proj = vp;
         queue.write_buffer(&self.view_data, 0, bytemuck::cast_slice(&[vp]));
     }
 
+    /// Return pipeline and mesh used to draw a system at the given LOD mesh
+    /// index, falling back to the main mesh.
+    pub fn lod_mesh(&self, idx: Option<usize>) -> (&wgpu::RenderPipeline, &ParticleMesh) {
+        match idx.and_then(|i| self.lod_meshes.get(i)) {
+            Some(lod) => (&lod.pipeline, &lod.mesh),
+            None => (&self.pipeline, &self.mesh),
+        }
+    }
+
     pub fn set_view_pos(&mut self, queue: &wgpu::Queue, vp: [f32; 3]) {
         let vp = [vp[0], vp[1], vp[2], 0.0];
+        self.view.view_pos = vp;
         queue.write_buffer(&self.view_data, 64, bytemuck::cast_slice(&[vp]));
     }
 }
 
+/// Cheaper mesh drawn for distant systems, with a pipeline matching its topology.
+pub struct ParticleLodMesh {
+    pub mesh:     ParticleMesh,
+    pub pipeline: wgpu::RenderPipeline,
+}
+
+/// Owned copy of the descriptor a renderer was built from, used to rebuild it.
+#[derive(Clone)]
+struct RendererSource {
+    format:     wgpu::TextureFormat,
+    texture:    Option<String>,
+    mesh:       MeshSource,
+    max_lights: usize,
+    depth:      Option<wgpu::TextureFormat>,
+    shader:     Option<String>,
+    attributes: u32,
+    lod_meshes: Vec<MeshSource>,
+}
+impl RendererSource {
+    fn new(format: wgpu::TextureFormat, desc: &ParticleSystemRendererDescriptor) -> Self {
+        Self {
+            format,
+            texture:    desc.texture.map(String::from),
+            mesh:       MeshSource::from(&desc.mesh_type),
+            max_lights: desc.max_lights,
+            depth:      desc.depth_texture.as_ref().map(|depth| depth.texture_format),
+            shader:     desc.shader.map(String::from),
+            attributes: desc.attributes,
+            lod_meshes: desc.lod_meshes.iter().map(MeshSource::from).collect(),
+        }
+    }
+
+    fn descriptor<'a>(&'a self, lod_meshes: &'a [ParticleMeshType<'a>]) -> ParticleSystemRendererDescriptor<'a> {
+        ParticleSystemRendererDescriptor {
+            texture: self.texture.as_deref(),
+            mesh_type: self.mesh.mesh_type(),
+            max_lights: self.max_lights,
+            depth_texture: self.depth.map(|texture_format| DepthTextureDescriptor { texture_format }),
+            shader: self.shader.as_deref(),
+            attributes: self.attributes,
+            lod_meshes,
+        }
+    }
+
+    /// Return paths of files the renderer was built from.
+    fn files(&self) -> impl Iterator<Item = &str> {
+        let meshes = std::iter::once(&self.mesh)
+            .chain(self.lod_meshes.iter())
+            .filter_map(|mesh| match mesh {
+                MeshSource::Custom(path) => Some(path.as_str()),
+                _ => None,
+            });
+        self.texture.as_deref().into_iter()
+            .chain(meshes)
+            .chain(self.shader.as_deref())
+    }
+}
+
+#[derive(Clone)]
+enum MeshSource {
+    Cube,
+    Point,
+    Custom(String),
+}
+impl MeshSource {
+    fn mesh_type(&self) -> ParticleMeshType<'_> {
+        match self {
+            MeshSource::Cube => ParticleMeshType::Cube,
+            MeshSource::Point => ParticleMeshType::Point,
+            MeshSource::Custom(path) => ParticleMeshType::Custom(path),
+        }
+    }
+}
+impl From<&ParticleMeshType<'_>> for MeshSource {
+    fn from(mesh_type: &ParticleMeshType) -> Self {
+        match mesh_type {
+            ParticleMeshType::Cube => MeshSource::Cube,
+            ParticleMeshType::Point => MeshSource::Point,
+            ParticleMeshType::Custom(path) => MeshSource::Custom(path.to_string()),
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Copy, Clone, Default, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Light {
@@ -324,16 +561,13 @@ impl From<&ParticleMeshType<'_>> for wgpu::PrimitiveTopology {
 
 
 fn from_depth_texture_desc(depth_texture: &Option<DepthTextureDescriptor>) -> Option<wgpu::DepthStencilState> {
-    if let Some(desc) = depth_texture {
-        Some(wgpu::DepthStencilState {
+    depth_texture.as_ref().map(|desc| {
+        wgpu::DepthStencilState {
             format: desc.texture_format,
             depth_write_enabled: true,
             depth_compare: wgpu::CompareFunction::Less,
             stencil: wgpu::StencilState::default(),
             bias: wgpu::DepthBiasState::default(),
-        })
-    }
-    else {
-        None
-    }   
+        }
+    })
 }
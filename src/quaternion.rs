@@ -1,6 +1,8 @@
+use std::ops::Mul;
+
 use crate::vector::Vec3;
 
-#[derive(Clone, Copy, Default)]
+#[derive(Clone, Copy, Default, PartialEq)]
 pub struct Quaternion {
     pub s: f32,
     pub v: Vec3,
@@ -18,4 +20,70 @@ impl Quaternion {
             v: Vec3::zero(),
         }
     }
-}
\ No newline at end of file
+    pub fn identity() -> Self {
+        Self::new(1.0, 0.0, 0.0, 0.0)
+    }
+    pub fn is_zero(&self) -> bool {
+        self.s == 0.0 && self.v == Vec3::zero()
+    }
+    pub fn len(&self) -> f32 {
+        (self.s*self.s + self.v.len_sq()).sqrt()
+    }
+    /// Return unit quaternion, or the identity if the length is zero.
+    pub fn normalized(&self) -> Self {
+        let len = self.len();
+        if len == 0.0 {
+            return Self::identity();
+        }
+        Self {
+            s: self.s / len,
+            v: self.v / len,
+        }
+    }
+    pub fn conjugate(&self) -> Self {
+        Self {
+            s: self.s,
+            v: -self.v,
+        }
+    }
+    /// Rotate vector by this quaternion, which is assumed to be normalized.
+    pub fn rotate(&self, vec: Vec3) -> Vec3 {
+        let t = self.v.cross(vec) * 2.0;
+        vec + t * self.s + self.v.cross(t)
+    }
+}
+
+impl Mul for Quaternion {
+    type Output = Self;
+
+    fn mul(self, other: Self) -> Self::Output {
+        Self {
+            s: self.s * other.s - self.v.dot(other.v),
+            v: other.v * self.s + self.v * other.s + self.v.cross(other.v),
+        }
+    }
+}
+
+impl From<[f32; 4]> for Quaternion {
+    fn from(arr: [f32; 4]) -> Quaternion {
+        Quaternion::new(arr[0], arr[1], arr[2], arr[3])
+    }
+}
+
+impl From<Quaternion> for [f32; 4] {
+    fn from(quat: Quaternion) -> [f32; 4] {
+        [quat.s, quat.v.x, quat.v.y, quat.v.z]
+    }
+}
+
+#[test]
+fn rotate_test() {
+    // Quarter turn about the z axis.
+    let half = std::f32::consts::FRAC_PI_4;
+    let quat = Quaternion::new(half.cos(), 0.0, 0.0, half.sin());
+    let v = quat.rotate(Vec3::new(1.0, 0.0, 0.0));
+
+    assert!((v - Vec3::new(0.0, 1.0, 0.0)).len() < 0.00001);
+    assert!(((quat * quat.conjugate()).s - 1.0).abs() < 0.00001);
+    assert!(Quaternion::zero().normalized() == Quaternion::identity());
+}
@@ -17,12 +17,22 @@ impl Randf32 {
             state: 555555555,
         }
     }
+    pub fn from_state(state: u64) -> Self {
+        Self { state }
+    }
+    pub fn state(&self) -> u64 {
+        self.state
+    }
     pub fn next(&mut self) -> f32 {
         self.state ^= self.state << 13;
         self.state ^= self.state >> 7;
         self.state ^= self.state << 17;
         ((self.state >> 11) as f64 / F64_MANTISSA) as f32
     }
+    pub fn next_u32(&mut self) -> u32 {
+        self.next();
+        (self.state >> 32) as u32
+    }
     pub fn next_in(&mut self, range: Range<f32>) -> f32 {
         (range.end - range.start) * self.next() + range.start
     }
@@ -53,6 +63,16 @@ impl Randf32 {
         )
     }
 }
+/// Return a number in [0,1) that is the same for the same seed.
+pub fn hash_f32(seed: u64) -> f32 {
+    // SplitMix64 finalizer.
+    let mut x = seed.wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^= x >> 31;
+    ((x >> 11) as f64 / F64_MANTISSA) as f32
+}
+
 impl Default for Randf32 {
     fn default() -> Self {
         Self::new()
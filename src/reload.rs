@@ -0,0 +1,89 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Detects changed files by polling their modification times, so no
+/// platform file watcher is required.
+#[derive(Clone, Debug, Default)]
+pub struct FileWatcher {
+    files: Vec<WatchedFile>,
+}
+impl FileWatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start watching a file. Watching a file twice has no effect.
+    pub fn watch<P: AsRef<Path>>(&mut self, path: P) {
+        let path = path.as_ref();
+        if self.files.iter().all(|file| file.path != path) {
+            self.files.push(
+                WatchedFile {
+                    path:     path.to_path_buf(),
+                    modified: modified(path),
+                }
+            );
+        }
+    }
+
+    pub fn unwatch<P: AsRef<Path>>(&mut self, path: P) {
+        self.files.retain(|file| file.path != path.as_ref());
+    }
+
+    /// Forget when a file was last modified, so it is reported as changed
+    /// by the next poll if it exists, e.g. after failing to parse it.
+    pub fn invalidate<P: AsRef<Path>>(&mut self, path: P) {
+        for file in self.files.iter_mut().filter(|file| file.path == path.as_ref()) {
+            file.modified = None;
+        }
+    }
+
+    /// Return paths of watched files.
+    pub fn files(&self) -> impl Iterator<Item = &Path> {
+        self.files.iter().map(|file| file.path.as_path())
+    }
+
+    /// Return files modified, created or removed since they were last polled.
+    pub fn poll_changes(&mut self) -> Vec<PathBuf> {
+        let mut changed = Vec::new();
+        for file in self.files.iter_mut() {
+            let time = modified(&file.path);
+            if time != file.modified {
+                file.modified = time;
+                changed.push(file.path.clone());
+            }
+        }
+        changed
+    }
+}
+
+#[derive(Clone, Debug)]
+struct WatchedFile {
+    path:     PathBuf,
+    modified: Option<SystemTime>,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+
+#[test]
+fn poll_file_changes() {
+    let path = std::env::temp_dir().join(format!("brumous_watch_{}.txt", std::process::id()));
+    fs::write(&path, "a").unwrap();
+
+    let mut watcher = FileWatcher::new();
+    watcher.watch(&path);
+    watcher.watch(&path);
+    assert!(watcher.files().count() == 1);
+    assert!(watcher.poll_changes().is_empty());
+
+    fs::remove_file(&path).unwrap();
+    assert!(watcher.poll_changes() == vec![path.clone()]);
+    assert!(watcher.poll_changes().is_empty());
+
+    fs::write(&path, "b").unwrap();
+    assert!(watcher.poll_changes() == vec![path.clone()]);
+    fs::remove_file(&path).unwrap();
+}
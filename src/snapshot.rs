@@ -0,0 +1,89 @@
+use crate::particle::Particle;
+use crate::particle_system::{ParticleAttribute, PlaybackState};
+use crate::quaternion::Quaternion;
+use crate::vector::{Vec3, Vec4};
+
+/// Full simulation state of a particle system, returned by
+/// [`ParticleSystem::snapshot`] and applied with [`ParticleSystem::restore`].
+///
+/// Settings that are not simulation state, such as the rate, bounds,
+/// colliders and animations, are not included.
+///
+/// [`ParticleSystem::snapshot`]: crate::particle_system::ParticleSystem::snapshot
+/// [`ParticleSystem::restore`]: crate::particle_system::ParticleSystem::restore
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ParticleSystemSnapshot {
+    pub particles:     Vec<ParticleSnapshot>,
+    /// Values of named attributes, in the same order as `particles`.
+    pub attributes:    Vec<AttributeSnapshot>,
+    pub rand_state:    u64,
+    /// Remaining emission time in seconds.
+    pub life:          f32,
+    pub state:         PlaybackState,
+    pub position:      [f32; 3],
+    /// Rotation quaternion in [s, x, y, z] order.
+    pub rotation:      [f32; 4],
+    pub scale:         [f32; 3],
+    pub prev_position: [f32; 3],
+    pub emit_carry:    f32,
+    /// Seconds simulated, the `time` of expressions.
+    pub time:          f32,
+    pub lod_frames:    u32,
+    pub lod_delta:     f32,
+    /// Attractor positions and masses.
+    pub attractors:    Vec<([f32; 3], f32)>,
+    pub forces:        Vec<[f32; 3]>,
+}
+
+/// State of a single particle.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ParticleSnapshot {
+    pub position: [f32; 3],
+    pub velocity: [f32; 3],
+    pub rotation: [f32; 4],
+    pub scale:    f32,
+    pub life:     f32,
+    pub age:      f32,
+    pub mass:     f32,
+    pub color:    [f32; 4],
+    pub seed:     u32,
+}
+impl ParticleSnapshot {
+    pub(crate) fn new(p: &Particle) -> Self {
+        Self {
+            position: p.position.into(),
+            velocity: p.velocity.into(),
+            rotation: p.rotation.into(),
+            scale:    p.scale,
+            life:     p.life,
+            age:      p.age,
+            mass:     p.mass,
+            color:    p.color.into(),
+            seed:     p.seed,
+        }
+    }
+
+    pub(crate) fn particle(&self) -> Particle {
+        Particle {
+            position: Vec3::from(self.position),
+            velocity: Vec3::from(self.velocity),
+            rotation: Quaternion::from(self.rotation),
+            scale:    self.scale,
+            life:     self.life,
+            age:      self.age,
+            mass:     self.mass,
+            color:    Vec4::from(self.color),
+            seed:     self.seed,
+        }
+    }
+}
+
+/// Values of a named per-particle attribute.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AttributeSnapshot {
+    pub name:   String,
+    pub values: Vec<ParticleAttribute>,
+}
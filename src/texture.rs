@@ -6,6 +6,7 @@ use crate::error::{BrumousError, BrumousResult};
 use image::GenericImageView;
 
 pub struct Texture {
+    #[allow(dead_code)]
     pub texture: wgpu::Texture,
     pub view:    wgpu::TextureView,
     pub sampler: wgpu::Sampler,
@@ -17,7 +18,7 @@ impl Texture {
                 .map_err(|e| BrumousError::OpenTexture(path.to_string(), e))?;
 
             let img = image::load_from_memory(&data)
-                .map_err(|e| BrumousError::LoadTexture(path.to_string(), e))?;
+                .map_err(|e| BrumousError::LoadTexture(path.to_string(), e.to_string()))?;
     
             let rgba = img.to_rgba8();
             let dimensions = img.dimensions();
@@ -0,0 +1,92 @@
+use std::ops::Mul;
+
+use crate::vector::Vec3;
+use crate::quaternion::Quaternion;
+use crate::matrix::Mat4x4;
+
+/// Translation, rotation and scale of a particle emitter.
+///
+/// Rotations are quaternions in `[s, x, y, z]` order, matching the
+/// rotation bounds of `ParticleSystemBounds`.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Transform {
+    pub(crate) translation: Vec3,
+    pub(crate) rotation:    Quaternion,
+    pub(crate) scale:       Vec3,
+}
+impl Transform {
+    pub fn new(translation: [f32; 3], rotation: [f32; 4], scale: [f32; 3]) -> Self {
+        Self {
+            translation: translation.into(),
+            rotation:    Quaternion::from(rotation).normalized(),
+            scale:       scale.into(),
+        }
+    }
+
+    pub fn identity() -> Self {
+        Self {
+            translation: Vec3::zero(),
+            rotation:    Quaternion::identity(),
+            scale:       Vec3::new(1.0, 1.0, 1.0),
+        }
+    }
+
+    pub fn translation(&self) -> [f32; 3] {
+        self.translation.into()
+    }
+
+    pub fn rotation(&self) -> [f32; 4] {
+        self.rotation.into()
+    }
+
+    pub fn scale(&self) -> [f32; 3] {
+        self.scale.into()
+    }
+
+    /// Transform a point from local space to parent space.
+    pub fn transform_point(&self, p: Vec3) -> Vec3 {
+        self.translation + self.transform_vector(p)
+    }
+
+    /// Transform a direction from local space to parent space, ignoring translation.
+    pub fn transform_vector(&self, v: Vec3) -> Vec3 {
+        self.rotation.rotate(v.scaled(self.scale))
+    }
+
+    pub fn matrix(&self) -> Mat4x4 {
+        Mat4x4::from_translation(self.translation) *
+        Mat4x4::from(self.rotation) *
+        Mat4x4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+}
+impl Default for Transform {
+    fn default() -> Self {
+        Self::identity()
+    }
+}
+
+impl Mul for Transform {
+    type Output = Self;
+
+    /// Combine a parent transform with a child transform. Non-uniform
+    /// parent scale is applied to the child's translation only.
+    fn mul(self, child: Self) -> Self::Output {
+        Self {
+            translation: self.transform_point(child.translation),
+            rotation:    self.rotation * child.rotation,
+            scale:       self.scale.scaled(child.scale),
+        }
+    }
+}
+
+#[test]
+fn combine_transforms() {
+    let half = std::f32::consts::FRAC_PI_4;
+    let parent = Transform::new([1.0, 0.0, 0.0], [half.cos(), 0.0, 0.0, half.sin()], [2.0, 2.0, 2.0]);
+    let child = Transform::new([1.0, 0.0, 0.0], [1.0, 0.0, 0.0, 0.0], [1.0, 1.0, 1.0]);
+
+    // Child offset is scaled by 2 and rotated a quarter turn about z.
+    let world = parent * child;
+    assert!((world.translation - Vec3::new(1.0, 2.0, 0.0)).len() < 0.00001);
+    assert!(world.scale == Vec3::new(2.0, 2.0, 2.0));
+}
@@ -85,6 +85,16 @@ impl Vec3 {
         let b = self.dot(normal);
         *self - (normal * (b * 2.0))
     }
+
+    /// Multiply each component by the matching component of vec.
+    pub fn scaled(&self, vec: Vec3) -> Self {
+        Self::new(self.x * vec.x, self.y * vec.y, self.z * vec.z)
+    }
+
+    /// Linearly interpolate between self and vec.
+    pub fn lerp(&self, vec: Vec3, t: f32) -> Self {
+        *self + (vec - *self) * t
+    }
 }
 
 impl Neg for Vec3 {
@@ -151,6 +161,12 @@ impl From<[f32; 3]> for Vec3 {
     }
 }
 
+impl From<Vec3> for [f32; 3] {
+    fn from(vec: Vec3) -> [f32; 3] {
+        [vec.x, vec.y, vec.z]
+    }
+}
+
 impl From<(f32, f32, f32)> for Vec3 {
     fn from(t: (f32, f32, f32)) -> Vec3 {
         Vec3::new(t.0, t.1, t.2)
@@ -188,6 +204,15 @@ fn cross_test() {
     assert!(cross == Vec3::new(-45.0, 0.0, 30.0));
 }
 
+#[test]
+fn lerp_test() {
+    let v  = Vec3::new(0.0, 2.0, -4.0);
+    let v2 = Vec3::new(4.0, 2.0, 4.0);
+
+    assert!(v.lerp(v2, 0.25) == Vec3::new(1.0, 2.0, -2.0));
+    assert!(v.lerp(v2, 1.0) == v2);
+}
+
 #[test]
 fn dot_test() {
     let v  = Vec3::new(4.0, 3.0, 6.0);